@@ -7,4 +7,7 @@ service.load_model("FR", model_name, "../data/models/country=FR/_model.pb")
 knn_result = service.query("FR", 782, 10, [(782, 439154173303199114, 1580637528, 2)])
 print(knn_result)
 knn_tf_result = service.tf_query("FR", 782, 10, [(782, 439154173303199114, 1580637528, 2)], model_name)
-print(knn_tf_result)
\ No newline at end of file
+print(knn_tf_result)
+# the same query gives the same result at any time with a reference timestamp
+knn_replay_result = service.tf_query("FR", 782, 10, [(782, 439154173303199114, 1580637528, 2)], model_name, reference_timestamp=1580640000)
+print(knn_replay_result)
\ No newline at end of file
@@ -1,14 +1,15 @@
-extern crate hnsw_rs;
+extern crate knn_rs;
 extern crate pyo3;
 extern crate shellexpand;
-extern crate tensorflow_sys;
 
-use pyo3::prelude::*;
-use pyo3::exceptions::TypeError;
-use hnsw_rs::*;
-use hnsw_rs::knnservice::Model;
 use env_logger::Env;
-use hnsw_rs::embedding_computer::UserEvent;
+use knn_rs::embedding_computer::{ComputeOptions, UserEvent};
+use knn_rs::knnservice::{Model, ModelType};
+use pyo3::exceptions::PyTypeError;
+use pyo3::prelude::*;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 struct PyUserEvent(UserEvent);
 
@@ -20,50 +21,94 @@ impl From<(i32, i64, u64, i32)> for PyUserEvent {
     }
 }
 
+fn to_py_err<E: ToString>(e: E) -> PyErr {
+    PyErr::new::<PyTypeError, _>(e.to_string())
+}
+
 #[pyclass]
 struct KnnService {
-    knn_country: knncountry::KnnByCountry
+    countries: HashMap<String, knn_rs::knnservice::KnnService>
+}
+
+impl KnnService {
+    fn search(&self, country: &str, index: i32, result_count: usize, timeline: Vec<(i32, i64, u64, i32)>, model: Option<String>, reference_timestamp: Option<u64>) -> PyResult<Vec<(i64, f32)>> {
+        if let Some(service) = self.countries.get(country) {
+            let user_events: Vec<UserEvent> = timeline.into_iter().map(PyUserEvent::from).map(|pu| pu.0).collect();
+            let options = ComputeOptions {
+                reference_timestamp,
+                ..Default::default()
+            };
+            service.get_closest_items(&user_events, index, result_count, model, &options)
+                .map(|result| result.products.iter().map(|p| (p.label, p.score)).collect())
+                .map_err(to_py_err)
+        } else {
+            Ok(vec![])
+        }
+    }
 }
 
 #[pymethods]
 impl KnnService {
     #[new]
     fn new() -> Self {
-        let knn_country = knncountry::KnnByCountry::default();
         KnnService {
-            knn_country
+            countries: HashMap::new()
         }
     }
 
-    fn load_country(&mut self, country: String, index_path: String, embedding_path: String) -> PyResult<()> {
-        self.knn_country.load(&country, index_path, embedding_path, None)
-            .map_err(|e| PyErr::new::<TypeError, _>(e.to_string()))
+    /// Loads the indices of a country, searched with the average model by default.
+    /// The product embeddings are read from the indices, `embedding_path` is only kept
+    /// for compatibility and is ignored.
+    #[pyo3(signature = (country, index_path, embedding_path = None))]
+    fn load_country(&mut self, country: String, index_path: String, embedding_path: Option<String>) -> PyResult<()> {
+        if let Some(embedding_path) = embedding_path {
+            log::warn!("Ignoring embedding path {}, the embeddings are read from the indices", embedding_path);
+        }
+        let index_path = shellexpand::tilde(&index_path).to_string();
+        let mut service = knn_rs::knnservice::KnnService::new();
+        service.load_index(index_path, &Default::default()).map_err(to_py_err)?;
+        let average = Model {
+            name: "avg".into(),
+            model_path: None,
+            model_type: ModelType::Average,
+            is_default: true,
+            version: None,
+            signature: None,
+            tensor_names: HashMap::new(),
+            session: Default::default(),
+        };
+        service.load_model(average, None::<PathBuf>).map_err(to_py_err)?;
+        self.countries.insert(country, service);
+        Ok(())
     }
 
-    fn load_model(&mut self, country: String, model_name: String, model_path: String) -> PyResult<()> {
-        let model_path = shellexpand::tilde(&model_path).to_string();
-        self.knn_country.load_model(&country, &model_name, model_path)
-            .map_err(|e| PyErr::new::<TypeError, _>(e.to_string()))
+    #[pyo3(signature = (country, model_name, model_path, model_type = "tf"))]
+    fn load_model(&mut self, country: String, model_name: String, model_path: String, model_type: &str) -> PyResult<()> {
+        let model_path = PathBuf::from(shellexpand::tilde(&model_path).to_string());
+        let service = self.countries.get_mut(&country)
+            .ok_or_else(|| to_py_err(format!("country {} not available", country)))?;
+        let model = Model {
+            name: model_name,
+            model_path: Some(model_path.clone()),
+            model_type: ModelType::from_str(model_type).map_err(to_py_err)?,
+            is_default: false,
+            version: None,
+            signature: None,
+            tensor_names: HashMap::new(),
+            session: Default::default(),
+        };
+        service.load_model(model, Some(model_path)).map_err(to_py_err)
     }
 
-    fn query(&self, country: &str, index: i32, result_count: usize, timeline: Vec<(i32, i64, u64, i32)>) -> PyResult<Vec<(i64, f32)>> {
-        if let Some(service) = self.knn_country.get_service(country) {
-            let user_events: Vec<UserEvent> = timeline.into_iter().map(PyUserEvent::from).map(|pu| pu.0).collect();
-            service.get_closest_items(&user_events, index, result_count, Model::Average)
-               .map_err(|e| PyErr::new::<TypeError, _>(e.to_string()))
-        } else {
-            Ok(vec![])
-        }
+    /// `reference_timestamp` is the current time, in seconds, used by the model. Now when not set.
+    #[pyo3(signature = (country, index, result_count, timeline, reference_timestamp = None))]
+    fn query(&self, country: &str, index: i32, result_count: usize, timeline: Vec<(i32, i64, u64, i32)>, reference_timestamp: Option<u64>) -> PyResult<Vec<(i64, f32)>> {
+        self.search(country, index, result_count, timeline, None, reference_timestamp)
     }
 
-    fn tf_query(&self, country: &str, index: i32, result_count: usize, timeline: Vec<(i32, i64, u64, i32)>, model_name: String) -> PyResult<Vec<(i64, f32)>> {
-        if let Some(service) = self.knn_country.get_service(country) {
-            let user_events: Vec<UserEvent> = timeline.into_iter().map(PyUserEvent::from).map(|pu| pu.0).collect();
-            service.get_closest_items(&user_events, index, result_count, Model::Tensorflow(model_name))
-                .map_err(|e| PyErr::new::<TypeError, _>(e.to_string()))
-        } else {
-            Ok(vec![])
-        }
+    #[pyo3(signature = (country, index, result_count, timeline, model_name, reference_timestamp = None))]
+    fn tf_query(&self, country: &str, index: i32, result_count: usize, timeline: Vec<(i32, i64, u64, i32)>, model_name: String, reference_timestamp: Option<u64>) -> PyResult<Vec<(i64, f32)>> {
+        self.search(country, index, result_count, timeline, Some(model_name), reference_timestamp)
     }
 }
 
@@ -73,4 +118,4 @@ fn knn_python(_py: Python, m: &PyModule) -> PyResult<()> {
     env_logger::from_env(Env::default().default_filter_or("info")).init();
     m.add_class::<KnnService>()?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -5,9 +5,11 @@ extern crate knn_rs;
 use criterion::black_box;
 use criterion::Criterion;
 
-use knn_rs::embedding_computer::UserEvent;
+use knn_rs::embedding_computer::{ComputeOptions, UserEvent};
+use knn_rs::knn_tf::SessionConfig;
 use knn_rs::knncountry::{Config, KnnByCountry};
 use knn_rs::knnservice::*;
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::str::FromStr;
 
@@ -25,8 +27,13 @@ fn bench(c: &mut Criterion) {
             model_type: ModelType::Average,
             version: None,
             model_path: None,
+            signature: None,
+            tensor_names: HashMap::new(),
+            session: SessionConfig::default(),
         }],
         version: "20240124000000".into(),
+        experiment: None,
+        load_options: Default::default(),
     };
     let mut kc = KnnByCountry::new(config);
     kc.load().expect("Loading index");
@@ -55,7 +62,13 @@ fn bench(c: &mut Criterion) {
                 .take(NB_EMBEDDINGS)
                 .map(|t| t.clone())
                 .collect();
-            let r = knn_service.get_closest_items(&data, INDEX_ID, 20, Some("avg".into()));
+            let r = knn_service.get_closest_items(
+                &data,
+                INDEX_ID,
+                20,
+                Some("avg".into()),
+                &ComputeOptions::default(),
+            );
             black_box(r)
         })
     });
@@ -1,6 +1,9 @@
 use crate::knnindex::EmbeddingRegistry;
 use crate::KnnError;
 use ndarray::{Array1, ArrayView1};
+use std::time::SystemTime;
+
+const SECONDS_PER_DAY: f32 = 86400.0;
 
 pub struct EmbeddingResult {
     pub user_embedding: Vec<f32>,
@@ -15,12 +18,130 @@ pub struct UserEvent {
     pub event_type: i32,
 }
 
+/// Request parameters controlling which events are used to compute the user embedding
+/// and how they are weighted.
+#[derive(Debug, Clone, Default)]
+pub struct ComputeOptions {
+    /// Only the most recent events are kept.
+    pub number_last_events: Option<usize>,
+    /// Only the events of the last days are kept.
+    pub number_last_days: Option<u32>,
+    /// Half life in days of the exponential decay used by weighted computers.
+    pub half_life: Option<f32>,
+    /// When none of the events is known, search with a zero user embedding
+    /// (or return the default products of the index) instead of returning nothing.
+    pub allow_zero_user_embedding: bool,
+    /// Timestamp in seconds used as the current time by the filters and the models,
+    /// so that a request can be replayed with the same result. Now when not set.
+    pub reference_timestamp: Option<u64>,
+}
+
+impl ComputeOptions {
+    /// The reference timestamp, or the current time.
+    pub fn now(&self) -> u64 {
+        self.reference_timestamp.unwrap_or_else(current_timestamp)
+    }
+
+    /// Applies `number_last_days` then `number_last_events` to the timeline. The kept events
+    /// stay in their original order, which sequence models depend on.
+    pub fn filter_events(&self, user_events: &[UserEvent], now: u64) -> Vec<UserEvent> {
+        let mut events: Vec<UserEvent> = match self.number_last_days {
+            Some(days) => {
+                let min_timestamp = now.saturating_sub(days as u64 * SECONDS_PER_DAY as u64);
+                user_events
+                    .iter()
+                    .filter(|e| e.timestamp >= min_timestamp)
+                    .cloned()
+                    .collect()
+            }
+            None => user_events.to_vec(),
+        };
+        if let Some(number_last_events) = self.number_last_events {
+            if events.len() > number_last_events {
+                // Most recent first, the later of two events with the same timestamp first
+                let mut by_recency: Vec<usize> = (0..events.len()).collect();
+                by_recency.sort_by_key(|i| std::cmp::Reverse((events[*i].timestamp, *i)));
+                let mut kept = vec![false; events.len()];
+                for i in by_recency.into_iter().take(number_last_events) {
+                    kept[i] = true;
+                }
+                let mut kept = kept.into_iter();
+                events.retain(|_| kept.next().unwrap_or(false));
+            }
+        }
+        events
+    }
+}
+
+pub(crate) fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Source of the current time, in seconds, of requests without reference timestamp.
+pub trait Clock: Sync + Send {
+    fn now(&self) -> u64;
+}
+
+#[derive(Default)]
+pub struct SystemClock {}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        current_timestamp()
+    }
+}
+
+/// Always returns the same timestamp, for tests and replays.
+pub struct FixedClock(pub u64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Appends the embedding of each event to `embeddings`, or a zero vector when the product
+/// is unknown. Returns the number of known events.
+pub(crate) fn fetch_embeddings(
+    registry: &EmbeddingRegistry,
+    user_events: &[UserEvent],
+    embeddings: &mut Vec<f32>,
+) -> Result<usize, KnnError> {
+    let mut user_event_used = 0;
+    for user_event in user_events {
+        if let Some(mut emb) = registry.fetch_item(user_event.index, user_event.label)? {
+            user_event_used += 1;
+            embeddings.append(&mut emb)
+        } else {
+            embeddings.resize(embeddings.len() + registry.dim, 0f32)
+        }
+    }
+    Ok(user_event_used)
+}
+
 pub trait UserEmbeddingComputer: Sync + Send {
     fn compute_user_vector(
         &self,
         registry: &EmbeddingRegistry,
         user_events: &[UserEvent],
+        options: &ComputeOptions,
     ) -> Result<EmbeddingResult, KnnError>;
+
+    /// Computes the embeddings of several users, one per timeline.
+    fn compute_user_vectors(
+        &self,
+        registry: &EmbeddingRegistry,
+        timelines: &[Vec<UserEvent>],
+        options: &ComputeOptions,
+    ) -> Result<Vec<EmbeddingResult>, KnnError> {
+        timelines
+            .iter()
+            .map(|user_events| self.compute_user_vector(registry, user_events, options))
+            .collect()
+    }
 }
 
 #[derive(Default)]
@@ -31,6 +152,7 @@ impl UserEmbeddingComputer for AverageComputer {
         &self,
         registry: &EmbeddingRegistry,
         user_events: &[UserEvent],
+        _options: &ComputeOptions,
     ) -> Result<EmbeddingResult, KnnError> {
         let mut count = 0;
         let mut user_vector = Array1::<f32>::zeros(registry.dim);
@@ -52,3 +174,160 @@ impl UserEmbeddingComputer for AverageComputer {
         })
     }
 }
+
+/// Average of the event embeddings weighted by an exponential decay on the event age.
+/// Without half life, all events have the same weight.
+#[derive(Default)]
+pub struct WeightedAverageComputer {}
+
+impl WeightedAverageComputer {
+    fn weight(user_event: &UserEvent, now: u64, half_life: Option<f32>) -> f32 {
+        match half_life {
+            Some(half_life) if half_life > 0.0 => {
+                let age_days = now.saturating_sub(user_event.timestamp) as f32 / SECONDS_PER_DAY;
+                0.5f32.powf(age_days / half_life)
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+impl UserEmbeddingComputer for WeightedAverageComputer {
+    fn compute_user_vector(
+        &self,
+        registry: &EmbeddingRegistry,
+        user_events: &[UserEvent],
+        options: &ComputeOptions,
+    ) -> Result<EmbeddingResult, KnnError> {
+        let now = options.now();
+        let mut count = 0;
+        let mut total_weight = 0f32;
+        let mut user_vector = Array1::<f32>::zeros(registry.dim);
+
+        for user_event in user_events {
+            if let Some(data_vector) = registry.fetch_item(user_event.index, user_event.label)? {
+                count += 1;
+                let weight = WeightedAverageComputer::weight(user_event, now, options.half_life);
+                total_weight += weight;
+                let view = ArrayView1::from(data_vector.as_slice());
+                user_vector.scaled_add(weight, &view);
+            }
+        }
+        if total_weight > 0.0 {
+            user_vector /= total_weight;
+        }
+
+        Ok(EmbeddingResult {
+            user_embedding: user_vector.to_vec(),
+            user_event_used_count: count,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_600_000_000;
+    const DAY: u64 = 86400;
+
+    fn event(label: i64, timestamp: u64) -> UserEvent {
+        UserEvent {
+            index: 1,
+            label,
+            timestamp,
+            event_type: 0,
+        }
+    }
+
+    fn labels(events: &[UserEvent]) -> Vec<i64> {
+        events.iter().map(|e| e.label).collect()
+    }
+
+    #[test]
+    fn filter_events_keeps_everything_by_default() {
+        let events = [event(1, NOW), event(2, NOW - 10 * DAY)];
+        let filtered = ComputeOptions::default().filter_events(&events, NOW);
+        assert_eq!(labels(&filtered), vec![1, 2]);
+    }
+
+    #[test]
+    fn filter_events_drops_events_older_than_the_last_days() {
+        let events = [
+            event(1, NOW - 3 * DAY),
+            event(2, NOW - 2 * DAY),
+            event(3, NOW),
+        ];
+        let options = ComputeOptions {
+            number_last_days: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(labels(&options.filter_events(&events, NOW)), vec![2, 3]);
+    }
+
+    #[test]
+    fn filter_events_keeps_the_most_recent_events_in_order() {
+        let events = [
+            event(3, NOW),
+            event(2, NOW - 3),
+            event(1, NOW - 1),
+            event(4, NOW - 2),
+        ];
+        let options = ComputeOptions {
+            number_last_events: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(labels(&options.filter_events(&events, NOW)), vec![3, 1]);
+    }
+
+    #[test]
+    fn filter_events_keeps_the_later_of_events_with_the_same_timestamp() {
+        let events = [event(1, NOW), event(2, NOW), event(3, NOW - 1)];
+        let options = ComputeOptions {
+            number_last_events: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(labels(&options.filter_events(&events, NOW)), vec![2]);
+    }
+
+    #[test]
+    fn filter_events_applies_the_days_before_the_count() {
+        let events = [
+            event(1, NOW - 5 * DAY),
+            event(2, NOW - 4 * DAY),
+            event(3, NOW),
+        ];
+        let options = ComputeOptions {
+            number_last_days: Some(1),
+            number_last_events: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(labels(&options.filter_events(&events, NOW)), vec![3]);
+    }
+
+    #[test]
+    fn weight_halves_every_half_life() {
+        let weight = |age: u64, half_life| {
+            WeightedAverageComputer::weight(&event(1, NOW - age), NOW, half_life)
+        };
+        assert_eq!(weight(0, Some(2.0)), 1.0);
+        assert!((weight(2 * DAY, Some(2.0)) - 0.5).abs() < 1e-6);
+        assert!((weight(4 * DAY, Some(2.0)) - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn weight_is_constant_without_half_life() {
+        let old = event(1, NOW - 100 * DAY);
+        assert_eq!(WeightedAverageComputer::weight(&old, NOW, None), 1.0);
+        assert_eq!(WeightedAverageComputer::weight(&old, NOW, Some(0.0)), 1.0);
+    }
+
+    #[test]
+    fn weight_of_future_events_is_one() {
+        let future = event(1, NOW + DAY);
+        assert_eq!(
+            WeightedAverageComputer::weight(&future, NOW, Some(1.0)),
+            1.0
+        );
+    }
+}
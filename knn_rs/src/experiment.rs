@@ -0,0 +1,137 @@
+use crate::KnnError;
+use serde::Deserialize;
+
+/// Split of the requests without explicit model between several models.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Experiment {
+    pub name: String,
+    pub variants: Vec<Variant>,
+}
+
+/// A model and its share of the experiment traffic, relative to the other variants.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Variant {
+    pub model: String,
+    pub weight: u32,
+}
+
+impl Experiment {
+    pub fn validate(&self) -> Result<(), KnnError> {
+        if self.variants.iter().all(|v| v.weight == 0) {
+            return Err(KnnError::InvalidExperiment(
+                self.name.clone(),
+                "no variant has a positive weight".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Picks the variant of a routing key, a key always gets the same variant.
+    /// The experiment name is part of the hash so that experiments are independent.
+    pub fn variant(&self, key: &[u8]) -> &Variant {
+        let total: u64 = self.variants.iter().map(|v| v.weight as u64).sum();
+        let mut bucket = fnv1a(&[self.name.as_bytes(), key]) % total.max(1);
+        for variant in self.variants.iter() {
+            if bucket < variant.weight as u64 {
+                return variant;
+            }
+            bucket -= variant.weight as u64;
+        }
+        &self.variants[0]
+    }
+}
+
+/// 64 bits FNV-1a, stable across versions and platforms unlike the std hasher.
+/// The low bits of FNV-1a, used by the modulo, barely mix the input (the lowest one is the
+/// parity of the bytes), so the hash goes through the murmur3 finalizer.
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    let hash = parts
+        .iter()
+        .flat_map(|part| part.iter())
+        .fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(PRIME)
+        });
+    fmix64(hash)
+}
+
+fn fmix64(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn experiment(weights: &[(&str, u32)]) -> Experiment {
+        Experiment {
+            name: "exp".to_string(),
+            variants: weights
+                .iter()
+                .map(|(model, weight)| Variant {
+                    model: model.to_string(),
+                    weight: *weight,
+                })
+                .collect(),
+        }
+    }
+
+    fn share(experiment: &Experiment, model: &str) -> f64 {
+        let keys = 10_000;
+        let count = (0..keys)
+            .filter(|i| experiment.variant(format!("user-{}", i).as_bytes()).model == model)
+            .count();
+        count as f64 / keys as f64
+    }
+
+    #[test]
+    fn variant_is_stable_for_a_key() {
+        let experiment = experiment(&[("a", 1), ("b", 1), ("c", 1)]);
+        for i in 0..100 {
+            let key = format!("user-{}", i);
+            let first = &experiment.variant(key.as_bytes()).model;
+            assert_eq!(first, &experiment.variant(key.as_bytes()).model);
+        }
+    }
+
+    #[test]
+    fn variant_follows_the_weights() {
+        let experiment = experiment(&[("a", 1), ("b", 3)]);
+        assert!((share(&experiment, "a") - 0.25).abs() < 0.02);
+        assert!((share(&experiment, "b") - 0.75).abs() < 0.02);
+    }
+
+    #[test]
+    fn variant_never_picks_a_zero_weight() {
+        let experiment = experiment(&[("a", 0), ("b", 2), ("c", 0)]);
+        assert_eq!(share(&experiment, "b"), 1.0);
+    }
+
+    #[test]
+    fn variants_depend_on_the_experiment_name() {
+        let first = experiment(&[("a", 1), ("b", 1)]);
+        let mut second = first.clone();
+        second.name = "other".to_string();
+        let same = (0..1000)
+            .filter(|i| {
+                let key = format!("user-{}", i);
+                first.variant(key.as_bytes()) == second.variant(key.as_bytes())
+            })
+            .count();
+        assert!(same > 400 && same < 600);
+    }
+
+    #[test]
+    fn validate_rejects_experiments_without_weight() {
+        assert!(experiment(&[("a", 0)]).validate().is_err());
+        assert!(experiment(&[]).validate().is_err());
+        assert!(experiment(&[("a", 0), ("b", 1)]).validate().is_ok());
+    }
+}
@@ -0,0 +1,103 @@
+use crate::embedding_computer::{
+    fetch_embeddings, ComputeOptions, EmbeddingResult, UserEmbeddingComputer, UserEvent,
+};
+use crate::knnindex::EmbeddingRegistry;
+use crate::KnnError;
+use ort::session::{builder::GraphOptimizationLevel, Session};
+use ort::value::{DynValue, Tensor};
+use std::path::Path;
+
+/// Runs an ONNX graph on CPU. The graph takes the same inputs as the tensorflow models,
+/// inputs missing from the graph are not fed.
+pub struct KnnOnnx {
+    session: Session,
+}
+
+impl KnnOnnx {
+    const PRODUCT_EMBEDDINGS: &'static str = "product_embeddings";
+    const TIMESTAMPS: &'static str = "timestamps";
+    const CURRENT_TIMESTAMP: &'static str = "current_timestamp";
+    const NB_EVENT: &'static str = "nb_events";
+    const EVENT_TYPES: &'static str = "event_types";
+    const FETCH_NAME: &'static str = "user_embedding";
+    const MODEL_FILE: &'static str = "model.onnx";
+
+    /// `model_path` is either the .onnx file or a folder containing a `model.onnx` file.
+    pub fn load_model<P: AsRef<Path>>(model_path: P) -> Result<KnnOnnx, KnnError> {
+        let model_path = model_path.as_ref();
+        let model_file = if model_path.is_dir() {
+            model_path.join(KnnOnnx::MODEL_FILE)
+        } else {
+            model_path.to_path_buf()
+        };
+        let session = Session::builder()?
+            .with_optimization_level(GraphOptimizationLevel::Level3)?
+            .with_intra_threads(1)?
+            .commit_from_file(model_file)?;
+        if !session
+            .outputs
+            .iter()
+            .any(|o| o.name == KnnOnnx::FETCH_NAME)
+        {
+            return Err(KnnError::OnnxError(format!(
+                "output {} not found in model",
+                KnnOnnx::FETCH_NAME
+            )));
+        }
+        Ok(KnnOnnx { session })
+    }
+
+    fn has_input(&self, name: &str) -> bool {
+        self.session.inputs.iter().any(|i| i.name == name)
+    }
+}
+
+impl UserEmbeddingComputer for KnnOnnx {
+    fn compute_user_vector(
+        &self,
+        registry: &EmbeddingRegistry,
+        user_events: &[UserEvent],
+        options: &ComputeOptions,
+    ) -> Result<EmbeddingResult, KnnError> {
+        let len = user_events.len();
+        let mut product_embedding = Vec::with_capacity(registry.dim * len);
+        let user_event_used = fetch_embeddings(registry, user_events, &mut product_embedding)?;
+        let timestamps: Vec<i64> = user_events.iter().map(|u| u.timestamp as i64).collect();
+        let event_types: Vec<i64> = user_events.iter().map(|u| u.event_type as i64).collect();
+
+        let feeds: [(&str, DynValue); 5] = [
+            (
+                KnnOnnx::PRODUCT_EMBEDDINGS,
+                Tensor::from_array(([1, len, registry.dim], product_embedding))?.into_dyn(),
+            ),
+            (
+                KnnOnnx::TIMESTAMPS,
+                Tensor::from_array(([1, len], timestamps))?.into_dyn(),
+            ),
+            (
+                KnnOnnx::EVENT_TYPES,
+                Tensor::from_array(([1, len], event_types))?.into_dyn(),
+            ),
+            (
+                KnnOnnx::NB_EVENT,
+                Tensor::from_array(([0usize; 0], vec![len as i64]))?.into_dyn(),
+            ),
+            (
+                KnnOnnx::CURRENT_TIMESTAMP,
+                Tensor::from_array(([0usize; 0], vec![options.now() as i64]))?.into_dyn(),
+            ),
+        ];
+        let inputs: Vec<(&str, DynValue)> = feeds
+            .into_iter()
+            .filter(|(name, _)| self.has_input(name))
+            .collect();
+
+        let outputs = self.session.run(inputs)?;
+        let (_, user_embedding) = outputs[KnnOnnx::FETCH_NAME].try_extract_raw_tensor::<f32>()?;
+
+        Ok(EmbeddingResult {
+            user_embedding: user_embedding.to_vec(),
+            user_event_used_count: user_event_used,
+        })
+    }
+}
@@ -1,17 +1,51 @@
-use crate::embedding_computer::{EmbeddingResult, UserEmbeddingComputer, UserEvent};
+use crate::embedding_computer::{
+    current_timestamp, fetch_embeddings, ComputeOptions, EmbeddingResult, UserEmbeddingComputer,
+    UserEvent,
+};
 use crate::knnindex::EmbeddingRegistry;
+use crate::knnservice::Model;
 use crate::KnnError;
 use prost::Message;
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
-use std::time::SystemTime;
-use tensorflow::Tensor;
-use tensorflow::{Graph, ImportGraphDefOptions, Session, SessionOptions, SessionRunArgs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tensorflow::{
+    DataType, Graph, ImportGraphDefOptions, Output, SavedModelBundle, Session, SessionOptions,
+    SessionRunArgs, Tensor, DEFAULT_SERVING_SIGNATURE_DEF_KEY,
+};
 
+/// Tensorflow session options of a model, mapped to the `ConfigProto` of the sessions.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionConfig {
+    /// Threads used to parallelize a single op, tensorflow default when not set.
+    pub intra_op_threads: Option<i32>,
+    /// Threads used to run independent ops, tensorflow default when not set.
+    pub inter_op_threads: Option<i32>,
+    /// `SINGLE_THREADED_EXECUTOR` when not set, `DEFAULT` or empty for the default tensorflow
+    /// executor.
+    pub executor_type: Option<String>,
+    /// Number of sessions the queries are dispatched to, 1 when not set.
+    pub pool_size: Option<usize>,
+}
+
+/// Runs a tensorflow model, either a frozen GraphDef file or a SavedModel directory.
+///
+/// The inputs and the output are identified by a logical name (`product_embeddings`,
+/// `timestamps`, `current_timestamp`, `nb_events`, `event_types` and `user_embedding`),
+/// which `Model::tensor_names` can remap to the op name of a GraphDef or to the key of
+/// a SavedModel signature. Inputs missing from the model are not fed.
+/// The operations are resolved once, when the model is loaded.
 pub struct KnnTf {
     graph: Graph,
     session: Session,
+    feeds: HashMap<&'static str, Output>,
+    fetch: Output,
+    // Whether the model takes several timelines at once, see `compute_user_vectors`
+    batched: bool,
 }
 
 pub mod config {
@@ -19,17 +53,51 @@ pub mod config {
 }
 
 impl KnnTf {
-    const PRODUCT_EMBEDDINGS: &'static str = "knn/feed/product_embeddings";
-    const TIMESTAMPS: &'static str = "knn/feed/timestamps_sec";
-    const CURRENT_TIMESTAMP: &'static str = "knn/feed/current_timestamp_sec";
-    //const PUBLISHER_EMBEDDING:&'static str = "knn/feed/publisher_embedding";
-    const NB_EVENT: &'static str = "knn/feed/nb_events";
-    const EVENT_TYPES: &'static str = "knn/feed/event_types";
-    const FETCH_NAME: &'static str = "knn/fetch/user_embedding";
-    fn build_config() -> Result<SessionOptions, KnnError> {
+    const PRODUCT_EMBEDDINGS: &'static str = "product_embeddings";
+    const TIMESTAMPS: &'static str = "timestamps";
+    const CURRENT_TIMESTAMP: &'static str = "current_timestamp";
+    const NB_EVENT: &'static str = "nb_events";
+    const EVENT_TYPES: &'static str = "event_types";
+    const FETCH_NAME: &'static str = "user_embedding";
+    const INPUTS: [&'static str; 5] = [
+        KnnTf::PRODUCT_EMBEDDINGS,
+        KnnTf::TIMESTAMPS,
+        KnnTf::CURRENT_TIMESTAMP,
+        KnnTf::NB_EVENT,
+        KnnTf::EVENT_TYPES,
+    ];
+    const SAVED_MODEL_FILE: &'static str = "saved_model.pb";
+    const SERVE_TAG: &'static str = "serve";
+    const SINGLE_THREADED_EXECUTOR: &'static str = "SINGLE_THREADED_EXECUTOR";
+    const TF_DEFAULT_EXECUTOR: &'static str = "DEFAULT";
+
+    /// Op names of the frozen GraphDef models.
+    fn default_op_name(name: &str) -> &'static str {
+        match name {
+            KnnTf::PRODUCT_EMBEDDINGS => "knn/feed/product_embeddings",
+            KnnTf::TIMESTAMPS => "knn/feed/timestamps_sec",
+            KnnTf::CURRENT_TIMESTAMP => "knn/feed/current_timestamp_sec",
+            //"knn/feed/publisher_embedding"
+            KnnTf::NB_EVENT => "knn/feed/nb_events",
+            KnnTf::EVENT_TYPES => "knn/feed/event_types",
+            _ => "knn/fetch/user_embedding",
+        }
+    }
+
+    fn build_config(session_config: &SessionConfig) -> Result<SessionOptions, KnnError> {
         let config_proto = config::ConfigProto {
+            intra_op_parallelism_threads: session_config.intra_op_threads.unwrap_or(0),
+            inter_op_parallelism_threads: session_config.inter_op_threads.unwrap_or(0),
+            // Otherwise the first session sets the thread pools of every model
+            use_per_session_threads: session_config.intra_op_threads.is_some()
+                || session_config.inter_op_threads.is_some(),
             experimental: Some(config::config_proto::Experimental {
-                executor_type: "SINGLE_THREADED_EXECUTOR".into(),
+                executor_type: match session_config.executor_type.as_deref() {
+                    None => KnnTf::SINGLE_THREADED_EXECUTOR.into(),
+                    // tensorflow picks its default executor for an empty type
+                    Some(KnnTf::TF_DEFAULT_EXECUTOR) => String::new(),
+                    Some(executor_type) => executor_type.into(),
+                },
                 ..Default::default()
             }),
             ..Default::default()
@@ -41,87 +109,410 @@ impl KnnTf {
         Ok(session)
     }
 
-    pub fn load_model<P: AsRef<Path>>(model_path: P) -> Result<KnnTf, KnnError> {
+    /// Expected type, rank and last dimension (the embedding one) of the inputs and output.
+    fn expected_spec(name: &str, dim: usize) -> (DataType, Option<usize>, Option<usize>) {
+        match name {
+            KnnTf::PRODUCT_EMBEDDINGS => (DataType::Float, Some(3), Some(dim)),
+            KnnTf::TIMESTAMPS | KnnTf::EVENT_TYPES => (DataType::Int64, Some(2), None),
+            KnnTf::CURRENT_TIMESTAMP => (DataType::Int64, Some(0), None),
+            KnnTf::NB_EVENT => (DataType::Int64, None, None),
+            _ => (DataType::Float, None, Some(dim)),
+        }
+    }
+
+    /// Loads a SavedModel when `model_path` is a directory containing a `saved_model.pb`,
+    /// and a frozen GraphDef file otherwise.
+    ///
+    /// The model is checked against the embedding dimension `dim` of the indices and run once
+    /// on a dummy timeline, so that an invalid model fails here rather than on the first query.
+    pub fn load_model<P: AsRef<Path>>(
+        model_path: P,
+        model: &Model,
+        dim: usize,
+    ) -> Result<KnnTf, KnnError> {
         let model_path = model_path.as_ref();
+        let mut knn_tf = if model_path.join(KnnTf::SAVED_MODEL_FILE).is_file() {
+            KnnTf::load_saved_model(model_path, model)?
+        } else {
+            KnnTf::load_graph_def(model_path, model)?
+        };
+        knn_tf.validate(model, dim)?;
+        knn_tf.batched = knn_tf.has_batched_event_count()?;
+        knn_tf.smoke_test(model, dim)?;
+        Ok(knn_tf)
+    }
+
+    /// Models taking a rank 1 `nb_events` accept a batch of timelines, the other ones
+    /// have a scalar `nb_events` or none and are run once per timeline.
+    fn has_batched_event_count(&self) -> Result<bool, KnnError> {
+        match self.feeds.get(KnnTf::NB_EVENT) {
+            Some(output) => Ok(self.graph.tensor_shape(output.clone())?.dims() == Some(1)),
+            None => Ok(false),
+        }
+    }
+
+    fn load_graph_def(model_path: &Path, model: &Model) -> Result<KnnTf, KnnError> {
         let mut graph = Graph::new();
         let mut proto = Vec::new();
         File::open(model_path)?.read_to_end(&mut proto)?;
         let options = ImportGraphDefOptions::new();
         graph.import_graph_def(&proto, &options)?;
-        let session_options = KnnTf::build_config()?;
+
+        let op_name = |name: &str| {
+            model
+                .tensor_names
+                .get(name)
+                .map(String::as_str)
+                .unwrap_or_else(|| KnnTf::default_op_name(name))
+                .to_string()
+        };
+        let mut feeds = HashMap::new();
+        for input in KnnTf::INPUTS {
+            if let Some(operation) = graph.operation_by_name(&op_name(input))? {
+                feeds.insert(
+                    input,
+                    Output {
+                        operation,
+                        index: 0,
+                    },
+                );
+            }
+        }
+        let fetch = Output {
+            operation: graph.operation_by_name_required(&op_name(KnnTf::FETCH_NAME))?,
+            index: 0,
+        };
+
+        let session_options = KnnTf::build_config(&model.session)?;
         let session = Session::new(&session_options, &graph)?;
-        Ok(KnnTf { graph, session })
+        Ok(KnnTf {
+            graph,
+            session,
+            feeds,
+            fetch,
+            batched: false,
+        })
     }
-}
 
-impl UserEmbeddingComputer for KnnTf {
-    fn compute_user_vector(
-        &self,
-        registry: &EmbeddingRegistry,
-        user_events: &[UserEvent],
-    ) -> Result<EmbeddingResult, KnnError> {
-        let mut product_embedding = Vec::with_capacity(registry.dim * user_events.len());
-        let mut user_event_used = 0;
-        for user_event in user_events {
-            if let Some(mut emb) = registry.fetch_item(user_event.index, user_event.label)? {
-                user_event_used += 1;
-                product_embedding.append(&mut emb)
-            } else {
-                product_embedding.append(&mut vec![0f32; registry.dim])
+    /// Inputs and output are resolved from the `Model::signature` serving signature,
+    /// `serving_default` when not set.
+    fn load_saved_model(model_path: &Path, model: &Model) -> Result<KnnTf, KnnError> {
+        let mut graph = Graph::new();
+        let session_options = KnnTf::build_config(&model.session)?;
+        let bundle =
+            SavedModelBundle::load(&session_options, [KnnTf::SERVE_TAG], &mut graph, model_path)?;
+        let signature_name = model
+            .signature
+            .as_deref()
+            .unwrap_or(DEFAULT_SERVING_SIGNATURE_DEF_KEY);
+        let signature = bundle.meta_graph_def().get_signature(signature_name)?;
+
+        let key = |name: &'static str| {
+            model
+                .tensor_names
+                .get(name)
+                .map(String::as_str)
+                .unwrap_or(name)
+        };
+        let mut feeds = HashMap::new();
+        for input in KnnTf::INPUTS {
+            if let Some(info) = signature.inputs().get(key(input)) {
+                feeds.insert(
+                    input,
+                    Output {
+                        operation: graph.operation_by_name_required(&info.name().name)?,
+                        index: info.name().index,
+                    },
+                );
             }
         }
+        let output = signature.get_output(key(KnnTf::FETCH_NAME))?.name();
+        let fetch = Output {
+            operation: graph.operation_by_name_required(&output.name)?,
+            index: output.index,
+        };
+
+        Ok(KnnTf {
+            graph,
+            session: bundle.session,
+            feeds,
+            fetch,
+            batched: false,
+        })
+    }
+
+    /// Checks the type and the known dimensions of the inputs and output.
+    fn validate(&self, model: &Model, dim: usize) -> Result<(), KnnError> {
+        let tensors = self
+            .feeds
+            .iter()
+            .map(|(name, output)| (*name, output))
+            .chain(std::iter::once((KnnTf::FETCH_NAME, &self.fetch)));
+        for (name, output) in tensors {
+            let invalid = |reason: String| {
+                KnnError::InvalidModel(model.name.clone(), format!("{} {}", name, reason))
+            };
+            let (data_type, rank, last_dim) = KnnTf::expected_spec(name, dim);
+            let actual_type = output.operation.output_type(output.index as usize);
+            if actual_type != data_type {
+                return Err(invalid(format!(
+                    "has type {:?} instead of {:?}",
+                    actual_type, data_type
+                )));
+            }
+            let shape = self.graph.tensor_shape(output.clone())?;
+            let actual_rank = match shape.dims() {
+                Some(actual_rank) => actual_rank,
+                None => continue,
+            };
+            if let Some(rank) = rank.filter(|r| *r != actual_rank) {
+                return Err(invalid(format!(
+                    "has rank {} instead of {}",
+                    actual_rank, rank
+                )));
+            }
+            if let (Some(last_dim), Some(Some(actual_dim))) =
+                (last_dim, actual_rank.checked_sub(1).map(|i| shape[i]))
+            {
+                if actual_dim != last_dim as i64 {
+                    return Err(invalid(format!(
+                        "has dimension {} instead of {}",
+                        actual_dim, last_dim
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the model on a timeline with a single zero embedding, and on a batch of two
+    /// such timelines when the model is batched.
+    fn smoke_test(&self, model: &Model, dim: usize) -> Result<(), KnnError> {
+        let now = current_timestamp();
+        let batch = if self.batched { 2 } else { 1 };
+        let event_count = if self.batched {
+            Tensor::new(&[batch as u64]).with_values(&[1i64, 1])?
+        } else {
+            Tensor::from(1i64)
+        };
+        let user_embedding = self
+            .run(
+                &vec![0f32; batch * dim],
+                &vec![now as i64; batch],
+                &vec![0; batch],
+                event_count,
+                now,
+                batch,
+                1,
+                dim,
+            )
+            .map_err(|e| {
+                KnnError::InvalidModel(model.name.clone(), format!("inference failed: {}", e))
+            })?;
+        if user_embedding.len() != batch * dim {
+            return Err(KnnError::InvalidModel(
+                model.name.clone(),
+                format!(
+                    "user embeddings of {} timelines have {} values instead of {}",
+                    batch,
+                    user_embedding.len(),
+                    batch * dim
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Runs the model on `batch` timelines of `len` events, `event_count` being either a scalar
+    /// for a single timeline or the number of events of each timeline, and `now` the current
+    /// timestamp fed to the model.
+    #[allow(clippy::too_many_arguments)]
+    fn run(
+        &self,
+        product_embedding: &[f32],
+        timestamps: &[i64],
+        event_types: &[i64],
+        event_count: Tensor<i64>,
+        now: u64,
+        batch: usize,
+        len: usize,
+        dim: usize,
+    ) -> Result<Vec<f32>, KnnError> {
         let product_tensor: Tensor<f32> =
-            Tensor::new(&[1, user_events.len() as u64, registry.dim as u64])
-                .with_values(&product_embedding)?;
-        let event_count = Tensor::from(user_events.len() as i64);
-        let current_timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let current_timestamp_tensor = Tensor::from(current_timestamp as i64);
-        let timestamps: Vec<i64> = user_events.iter().map(|u| u.timestamp as i64).collect();
-        let timestamps_tensor =
-            Tensor::new(&[1, user_events.len() as u64]).with_values(timestamps.as_slice())?;
-        let event_types: Vec<i64> = user_events.iter().map(|u| u.event_type as i64).collect();
+            Tensor::new(&[batch as u64, len as u64, dim as u64]).with_values(product_embedding)?;
+        let current_timestamp_tensor = Tensor::from(now as i64);
+        let timestamps_tensor = Tensor::new(&[batch as u64, len as u64]).with_values(timestamps)?;
         let event_type_tensor =
-            Tensor::new(&[1, user_events.len() as u64]).with_values(event_types.as_slice())?;
+            Tensor::new(&[batch as u64, len as u64]).with_values(event_types)?;
 
         let mut session_args = SessionRunArgs::new();
-        if let Some(op) = self
-            .graph
-            .operation_by_name(KnnTf::PRODUCT_EMBEDDINGS)
-            .unwrap()
-        {
-            session_args.add_feed(&op, 0, &product_tensor);
+        if let Some(feed) = self.feeds.get(KnnTf::PRODUCT_EMBEDDINGS) {
+            session_args.add_feed(&feed.operation, feed.index, &product_tensor);
         }
-        if let Some(op) = self
-            .graph
-            .operation_by_name(KnnTf::CURRENT_TIMESTAMP)
-            .unwrap()
-        {
-            session_args.add_feed(&op, 0, &current_timestamp_tensor);
+        if let Some(feed) = self.feeds.get(KnnTf::CURRENT_TIMESTAMP) {
+            session_args.add_feed(&feed.operation, feed.index, &current_timestamp_tensor);
         }
-        if let Some(op) = self.graph.operation_by_name(KnnTf::TIMESTAMPS).unwrap() {
-            session_args.add_feed(&op, 0, &timestamps_tensor);
+        if let Some(feed) = self.feeds.get(KnnTf::TIMESTAMPS) {
+            session_args.add_feed(&feed.operation, feed.index, &timestamps_tensor);
         }
-        if let Some(op) = self.graph.operation_by_name(KnnTf::EVENT_TYPES).unwrap() {
-            session_args.add_feed(&op, 0, &event_type_tensor);
+        if let Some(feed) = self.feeds.get(KnnTf::EVENT_TYPES) {
+            session_args.add_feed(&feed.operation, feed.index, &event_type_tensor);
         }
-        if let Some(op) = self.graph.operation_by_name(KnnTf::NB_EVENT).unwrap() {
-            session_args.add_feed(&op, 0, &event_count);
+        if let Some(feed) = self.feeds.get(KnnTf::NB_EVENT) {
+            session_args.add_feed(&feed.operation, feed.index, &event_count);
         }
 
-        let fetch = session_args.request_fetch(
-            &self.graph.operation_by_name_required(KnnTf::FETCH_NAME)?,
-            0,
-        );
+        let fetch = session_args.request_fetch(&self.fetch.operation, self.fetch.index);
         self.session.run(&mut session_args)?;
         let result_tensor: Tensor<f32> = session_args.fetch(fetch)?;
-        let result_emb = result_tensor.to_vec();
+        Ok(result_tensor.to_vec())
+    }
+}
+
+impl UserEmbeddingComputer for KnnTf {
+    fn compute_user_vector(
+        &self,
+        registry: &EmbeddingRegistry,
+        user_events: &[UserEvent],
+        options: &ComputeOptions,
+    ) -> Result<EmbeddingResult, KnnError> {
+        let mut product_embedding = Vec::with_capacity(registry.dim * user_events.len());
+        let user_event_used = fetch_embeddings(registry, user_events, &mut product_embedding)?;
+        let timestamps: Vec<i64> = user_events.iter().map(|u| u.timestamp as i64).collect();
+        let event_types: Vec<i64> = user_events.iter().map(|u| u.event_type as i64).collect();
+        let event_count = Tensor::from(user_events.len() as i64);
+
+        let result_emb = self.run(
+            &product_embedding,
+            &timestamps,
+            &event_types,
+            event_count,
+            options.now(),
+            1,
+            user_events.len(),
+            registry.dim,
+        )?;
 
         Ok(EmbeddingResult {
             user_embedding: result_emb,
             user_event_used_count: user_event_used,
         })
     }
+
+    /// For batched models, timelines are padded with zero embeddings to the longest one, and
+    /// `nb_events` is fed with the number of events of each timeline: the model is expected
+    /// to ignore the events past it. The other models are run once per timeline.
+    fn compute_user_vectors(
+        &self,
+        registry: &EmbeddingRegistry,
+        timelines: &[Vec<UserEvent>],
+        options: &ComputeOptions,
+    ) -> Result<Vec<EmbeddingResult>, KnnError> {
+        if !self.batched || timelines.len() <= 1 {
+            return timelines
+                .iter()
+                .map(|user_events| self.compute_user_vector(registry, user_events, options))
+                .collect();
+        }
+        let batch = timelines.len();
+        let max_len = timelines.iter().map(|t| t.len()).max().unwrap_or(0);
+        let mut product_embedding = Vec::with_capacity(batch * max_len * registry.dim);
+        let mut timestamps = Vec::with_capacity(batch * max_len);
+        let mut event_types = Vec::with_capacity(batch * max_len);
+        let mut event_counts = Vec::with_capacity(batch);
+        let mut user_events_used = Vec::with_capacity(batch);
+        for user_events in timelines {
+            let padding = max_len - user_events.len();
+            user_events_used.push(fetch_embeddings(
+                registry,
+                user_events,
+                &mut product_embedding,
+            )?);
+            product_embedding.resize(product_embedding.len() + padding * registry.dim, 0f32);
+            timestamps.extend(user_events.iter().map(|u| u.timestamp as i64));
+            timestamps.resize(timestamps.len() + padding, 0);
+            event_types.extend(user_events.iter().map(|u| u.event_type as i64));
+            event_types.resize(event_types.len() + padding, 0);
+            event_counts.push(user_events.len() as i64);
+        }
+        let event_count = Tensor::new(&[batch as u64]).with_values(&event_counts)?;
+
+        let result_emb = self.run(
+            &product_embedding,
+            &timestamps,
+            &event_types,
+            event_count,
+            options.now(),
+            batch,
+            max_len,
+            registry.dim,
+        )?;
+        if result_emb.len() != batch * registry.dim {
+            return Err(KnnError::TFError(format!(
+                "user embeddings of {} timelines have {} values instead of {}",
+                batch,
+                result_emb.len(),
+                batch * registry.dim
+            )));
+        }
+        Ok(result_emb
+            .chunks(registry.dim.max(1))
+            .zip(user_events_used)
+            .map(|(user_embedding, user_event_used_count)| EmbeddingResult {
+                user_embedding: user_embedding.to_vec(),
+                user_event_used_count,
+            })
+            .collect())
+    }
+}
+
+/// Dispatches the queries round-robin to `SessionConfig::pool_size` instances of a model,
+/// each with its own session.
+pub struct KnnTfPool {
+    models: Vec<KnnTf>,
+    next: AtomicUsize,
+}
+
+impl KnnTfPool {
+    pub fn load_model<P: AsRef<Path>>(
+        model_path: P,
+        model: &Model,
+        dim: usize,
+    ) -> Result<KnnTfPool, KnnError> {
+        let pool_size = model.session.pool_size.unwrap_or(1).max(1);
+        let models = (0..pool_size)
+            .map(|_| KnnTf::load_model(model_path.as_ref(), model, dim))
+            .collect::<Result<Vec<KnnTf>, KnnError>>()?;
+        Ok(KnnTfPool {
+            models,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    fn next(&self) -> &KnnTf {
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        &self.models[i % self.models.len()]
+    }
+}
+
+impl UserEmbeddingComputer for KnnTfPool {
+    fn compute_user_vector(
+        &self,
+        registry: &EmbeddingRegistry,
+        user_events: &[UserEvent],
+        options: &ComputeOptions,
+    ) -> Result<EmbeddingResult, KnnError> {
+        self.next()
+            .compute_user_vector(registry, user_events, options)
+    }
+
+    fn compute_user_vectors(
+        &self,
+        registry: &EmbeddingRegistry,
+        timelines: &[Vec<UserEvent>],
+        options: &ComputeOptions,
+    ) -> Result<Vec<EmbeddingResult>, KnnError> {
+        self.next()
+            .compute_user_vectors(registry, timelines, options)
+    }
 }
@@ -1,10 +1,15 @@
 use serde::Deserialize;
 
+use crate::experiment::Experiment;
 use crate::knnservice::{KnnService, Model};
+use crate::loader::{parallel_map, LoadOptions};
 use crate::KnnError;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 
+/// Written in each country folder once its export is complete.
+const SUCCESS_MARKER: &str = "_SUCCESS";
+
 #[derive(Debug, Default, Clone, Deserialize)]
 pub struct Config {
     pub indices_root_path: PathBuf,
@@ -12,17 +17,64 @@ pub struct Config {
     pub platform: String,
     pub version: String,
     pub countries: Vec<String>,
+    /// Traffic split between models, applied to every country.
+    #[serde(default)]
+    pub experiment: Option<Experiment>,
+    #[serde(default)]
+    pub load_options: LoadOptions,
 }
 
 impl Config {
     fn indice_path(&self, country: &str) -> PathBuf {
+        self.indice_path_for_version(&self.version, country)
+    }
+
+    fn indice_path_for_version(&self, version: &str, country: &str) -> PathBuf {
         self.indices_root_path
             .join(self.platform.clone())
-            .join(self.version.clone())
+            .join(version)
             .join(format!("country={}", country))
     }
+
+    /// Returns the most recent version folder of the platform which is complete for every
+    /// configured country and isn't in `excluded`.
+    pub fn latest_version(&self, excluded: &HashSet<String>) -> Result<Option<String>, KnnError> {
+        Ok(self
+            .complete_versions()?
+            .into_iter()
+            .filter(|version| !excluded.contains(version))
+            .max())
+    }
+
+    /// Version folders of the platform in which the `_SUCCESS` marker, written once the
+    /// export is fully copied, and the metadata unless building the indices from Parquet
+    /// files, are available for every configured country.
+    pub fn complete_versions(&self) -> Result<Vec<String>, KnnError> {
+        let mut versions = vec![];
+        for entry in std::fs::read_dir(self.indices_root_path.join(self.platform.clone()))? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if let Some(version) = entry.file_name().to_str() {
+                let complete = self.countries.iter().all(|country| {
+                    let path = self.indice_path_for_version(version, country);
+                    path.join(SUCCESS_MARKER).exists()
+                        && (self.load_options.parquet.is_some()
+                            || path.join("metadata.json").exists())
+                });
+                if complete {
+                    versions.push(version.to_string());
+                }
+            }
+        }
+        Ok(versions)
+    }
 }
 
+/// Cloning is cheap as the services share their indices and models with the clone,
+/// which allows to update a copy while the original keeps serving.
+#[derive(Clone)]
 pub struct KnnByCountry {
     config: Config,
     countries: HashMap<String, KnnService>,
@@ -36,25 +88,94 @@ impl KnnByCountry {
         }
     }
 
+    /// Loads the configured countries, `LoadOptions::parallel_countries` at a time.
     pub fn load(&mut self) -> Result<(), KnnError> {
-        for country in self.config.countries.iter() {
-            let mut knn_service = KnnService::new();
-            knn_service.load_index(self.config.indice_path(country))?;
-
-            for m in self.config.models.iter() {
-                let mpath = m.model_path.as_ref().map(|mp| {
-                    mp.join(self.config.platform.clone())
-                        .join(m.version.as_ref().unwrap())
-                        .join(format!("country={}", country))
-                });
+        let services = parallel_map(
+            &self.config.countries,
+            self.config.load_options.parallel_countries(),
+            |country| self.load_service(country),
+        );
+        for (country, knn_service) in self.config.countries.iter().zip(services) {
+            self.countries.insert(country.to_string(), knn_service?);
+        }
+        Ok(())
+    }
 
-                knn_service.load_model(m.clone(), mpath)?;
-            }
-            self.countries.insert(country.to_string(), knn_service);
+    fn model_path(&self, model: &Model, country: &str) -> Result<Option<PathBuf>, KnnError> {
+        match (&model.model_path, &model.version) {
+            (Some(mp), Some(version)) => Ok(Some(
+                mp.join(self.config.platform.clone())
+                    .join(version)
+                    .join(format!("country={}", country)),
+            )),
+            (Some(_), None) => Err(KnnError::ModelVersionMissing(model.name.clone())),
+            (None, _) => Ok(None),
         }
+    }
+
+    fn load_service(&self, country: &str) -> Result<KnnService, KnnError> {
+        let mut knn_service = KnnService::new();
+        knn_service.load_index(self.config.indice_path(country), &self.config.load_options)?;
+
+        for m in self.config.models.iter() {
+            knn_service.load_model(m.clone(), self.model_path(m, country)?)?;
+        }
+        knn_service.set_experiment(self.config.experiment.clone())?;
+        Ok(knn_service)
+    }
+
+    /// Loads (or reloads) the indices of a country with the configured models.
+    pub fn load_country(&mut self, country: &str) -> Result<(), KnnError> {
+        let knn_service = self.load_service(country)?;
+        self.countries.insert(country.to_string(), knn_service);
+        if !self.config.countries.iter().any(|c| c == country) {
+            self.config.countries.push(country.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn unload_country(&mut self, country: &str) -> bool {
+        self.config.countries.retain(|c| c != country);
+        self.countries.remove(country).is_some()
+    }
+
+    /// Loads a model in every loaded country and adds it to the configuration,
+    /// replacing any model with the same name.
+    pub fn load_model(&mut self, model: Model) -> Result<(), KnnError> {
+        let mut countries = HashMap::with_capacity(self.countries.len());
+        for (country, knn_service) in self.countries.iter() {
+            let mut knn_service = knn_service.clone();
+            knn_service.load_model(model.clone(), self.model_path(&model, country)?)?;
+            countries.insert(country.clone(), knn_service);
+        }
+        self.countries = countries;
+
+        if model.is_default {
+            self.config
+                .models
+                .iter_mut()
+                .for_each(|m| m.is_default = false);
+        }
+        self.config.models.retain(|m| m.name != model.name);
+        self.config.models.push(model);
         Ok(())
     }
 
+    pub fn set_default_model(&mut self, model_name: &str) -> Result<(), KnnError> {
+        for knn_service in self.countries.values_mut() {
+            knn_service.set_default_model(model_name)?;
+        }
+        self.config
+            .models
+            .iter_mut()
+            .for_each(|m| m.is_default = m.name == model_name);
+        Ok(())
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
     pub fn get_service(&self, country: &str) -> Option<&KnnService> {
         self.countries
             .get(country)
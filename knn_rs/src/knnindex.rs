@@ -1,7 +1,7 @@
 use crate::*;
 use serde::Deserialize;
-use std::collections::BinaryHeap;
 use std::collections::HashMap;
+use std::sync::OnceLock;
 
 use self::productindex::IndexResult;
 use self::productindex::ProductIndex;
@@ -10,6 +10,8 @@ use self::wrappedindex::WrappedIndex;
 pub struct KnnIndex {
     indices: Vec<WrappedIndex>,
     extra_items: Vec<WrappedIndex>,
+    // Distinct labels of all the chunks, computed on first use
+    sorted_labels: OnceLock<Vec<i64>>,
 }
 
 #[derive(Deserialize)]
@@ -29,13 +31,7 @@ pub struct Metadata {
 
 impl ProductIndex for KnnIndex {
     fn count(&self) -> usize {
-        if let Some(i) = self.indices.first() {
-            return i.count();
-        }
-        if let Some(i) = self.extra_items.first() {
-            return i.count();
-        }
-        0
+        self.reco_count() + self.non_reco_count()
     }
 
     fn dimension(&self) -> usize {
@@ -73,18 +69,49 @@ impl ProductIndex for KnnIndex {
         Ok(None)
     }
 
+    fn get_norm(&self, label: i64) -> Result<Option<f32>, KnnError> {
+        for i in self.indices.iter() {
+            if let Some(v) = i.get_norm(label)? {
+                return Ok(Some(v));
+            }
+        }
+        for i in self.extra_items.iter() {
+            if let Some(v) = i.get_norm(label)? {
+                return Ok(Some(v));
+            }
+        }
+        Ok(None)
+    }
+
     fn search(&self, embedding: &[f32], nb_result: usize) -> Result<Vec<IndexResult>, KnnError> {
-        let mut heap = BinaryHeap::with_capacity(nb_result);
+        let mut results = Vec::with_capacity(nb_result * self.indices.len());
         for index in self.indices.iter() {
-            let res = index.search(embedding, nb_result)?;
-            res.into_iter().for_each(|result| {
-                if heap.len() >= nb_result {
-                    heap.pop();
-                }
-                heap.push(result);
-            });
+            results.append(&mut index.search(embedding, nb_result)?);
+        }
+        // Each chunk returns its own best results, keep the best ones across chunks
+        results.sort();
+        results.truncate(nb_result);
+        Ok(results)
+    }
+
+    fn search_batch(
+        &self,
+        embeddings: &[f32],
+        nq: usize,
+        nb_result: usize,
+    ) -> Result<Vec<Vec<IndexResult>>, KnnError> {
+        let mut results: Vec<Vec<IndexResult>> = (0..nq).map(|_| vec![]).collect();
+        for index in self.indices.iter() {
+            let res = index.search_batch(embeddings, nq, nb_result)?;
+            for (query_results, mut chunk_results) in results.iter_mut().zip(res) {
+                query_results.append(&mut chunk_results);
+            }
+        }
+        for query_results in results.iter_mut() {
+            query_results.sort();
+            query_results.truncate(nb_result);
         }
-        Ok(heap.into_vec())
+        Ok(results)
     }
 }
 
@@ -99,16 +126,47 @@ impl KnnIndex {
         KnnIndex {
             indices: vec![],
             extra_items: vec![],
+            sorted_labels: OnceLock::new(),
         }
     }
 
     pub fn add_reco_index(&mut self, wi: WrappedIndex) {
         self.indices.push(wi);
+        self.sorted_labels.take();
     }
 
     pub fn add_non_reco_index(&mut self, wi: WrappedIndex) {
         self.extra_items.push(wi);
+        self.sorted_labels.take();
     }
+
+    /// The distinct labels of the reco and non reco chunks in ascending order.
+    /// They are sorted once and kept with the index.
+    pub fn sorted_labels(&self) -> Result<&[i64], KnnError> {
+        if let Some(labels) = self.sorted_labels.get() {
+            return Ok(labels);
+        }
+        let mut labels = self.list_labels()?;
+        labels.sort_unstable();
+        labels.dedup();
+        // Another thread may have sorted them meanwhile, both lists are the same
+        Ok(self.sorted_labels.get_or_init(|| labels))
+    }
+
+    pub fn reco_count(&self) -> usize {
+        self.indices.iter().map(|i| i.count()).sum()
+    }
+
+    pub fn non_reco_count(&self) -> usize {
+        self.extra_items.iter().map(|i| i.count()).sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexStats {
+    pub index_id: i32,
+    pub reco_count: usize,
+    pub non_reco_count: usize,
 }
 
 #[derive(Default)]
@@ -130,6 +188,14 @@ impl EmbeddingRegistry {
         }
     }
 
+    pub fn sorted_labels(&self, index_id: i32) -> Result<&[i64], KnnError> {
+        if let Some(index) = self.embeddings.get(&index_id) {
+            index.sorted_labels()
+        } else {
+            Ok(&[])
+        }
+    }
+
     pub fn fetch_item(&self, index_id: i32, label: i64) -> Result<Option<Vec<f32>>, KnnError> {
         if let Some(index) = self.embeddings.get(&index_id) {
             index.get_item(label)
@@ -138,6 +204,28 @@ impl EmbeddingRegistry {
         }
     }
 
+    pub fn list_indices(&self) -> Vec<IndexStats> {
+        let mut stats: Vec<IndexStats> = self
+            .embeddings
+            .iter()
+            .map(|(index_id, index)| IndexStats {
+                index_id: *index_id,
+                reco_count: index.reco_count(),
+                non_reco_count: index.non_reco_count(),
+            })
+            .collect();
+        stats.sort_by_key(|s| s.index_id);
+        stats
+    }
+
+    pub fn fetch_norm(&self, index_id: i32, label: i64) -> Result<Option<f32>, KnnError> {
+        if let Some(index) = self.embeddings.get(&index_id) {
+            index.get_norm(label)
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn has_item(&self, index_id: i32, label: i64) -> Result<bool, KnnError> {
         self.fetch_item(index_id, label).map(|a| a.is_some())
     }
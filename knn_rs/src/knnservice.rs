@@ -1,16 +1,23 @@
 use crate::embedding_computer::{
-    AverageComputer, EmbeddingResult, UserEmbeddingComputer, UserEvent,
+    AverageComputer, Clock, ComputeOptions, EmbeddingResult, SystemClock, UserEmbeddingComputer,
+    UserEvent, WeightedAverageComputer,
 };
-use crate::knn_tf::KnnTf;
-use crate::knnindex::EmbeddingRegistry;
-use crate::loader::Loader;
+use crate::experiment::Experiment;
+use crate::knn_tf::{KnnTfPool, SessionConfig};
+use crate::knnindex::{EmbeddingRegistry, IndexStats};
+use crate::loader::{LoadOptions, LoadReport, Loader};
 use crate::productindex::ProductIndex;
 use crate::*;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::fmt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use self::productindex::IndexResult;
+use self::productindex::{squared_l2_norm, IndexResult};
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -20,13 +27,26 @@ pub struct Model {
     pub model_type: ModelType,
     pub is_default: bool,
     pub version: Option<String>,
+    /// Serving signature of tensorflow SavedModel models, `serving_default` when not set.
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// Renames the inputs and output of tensorflow models (`product_embeddings`, `timestamps`,
+    /// `current_timestamp`, `nb_events`, `event_types`, `user_embedding`) to the op names
+    /// of the graph or to the keys of the SavedModel signature.
+    #[serde(default)]
+    pub tensor_names: HashMap<String, String>,
+    /// Session options of tensorflow models.
+    #[serde(default)]
+    pub session: SessionConfig,
 }
 
 #[derive(Debug, Eq, PartialEq, Hash, Clone, Deserialize)]
 pub enum ModelType {
     Average,
+    WeightedAverage,
     Tensorflow,
     XLA,
+    Onnx,
 }
 
 impl FromStr for ModelType {
@@ -35,17 +55,59 @@ impl FromStr for ModelType {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "average" | "avg" => Ok(ModelType::Average),
+            "weighted_average" | "wavg" => Ok(ModelType::WeightedAverage),
             "tf" | "tensorflow" => Ok(ModelType::Tensorflow),
             "xla" => Ok(ModelType::XLA),
+            "onnx" => Ok(ModelType::Onnx),
             _ => Err(KnnError::ModelNotFound(s.to_string())),
         }
     }
 }
 
+impl fmt::Display for ModelType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ModelType::Average => "avg",
+            ModelType::WeightedAverage => "wavg",
+            ModelType::Tensorflow => "tf",
+            ModelType::XLA => "xla",
+            ModelType::Onnx => "onnx",
+        };
+        f.write_str(name)
+    }
+}
+
+pub struct KnnResult {
+    pub products: Vec<IndexResult>,
+    pub user_events_used_count: usize,
+    pub squared_l2_query_norm: f32,
+}
+
+impl KnnResult {
+    fn new(products: Vec<IndexResult>, user_vector: &EmbeddingResult) -> KnnResult {
+        let squared_l2_query_norm = if user_vector.user_event_used_count == 0 {
+            0f32
+        } else {
+            squared_l2_norm(&user_vector.user_embedding)
+        };
+        KnnResult {
+            products,
+            user_events_used_count: user_vector.user_event_used_count,
+            squared_l2_query_norm,
+        }
+    }
+}
+
+/// Cloning a service is cheap: indices and models are shared with the clone.
+#[derive(Clone)]
 pub struct KnnService {
-    embedding_registry: Option<EmbeddingRegistry>,
+    embedding_registry: Option<Arc<EmbeddingRegistry>>,
+    default_products: HashMap<i32, Vec<i64>>,
     default_model: Option<String>,
-    models: HashMap<String, Box<dyn UserEmbeddingComputer>>,
+    models: HashMap<String, (Model, Arc<dyn UserEmbeddingComputer>)>,
+    clock: Arc<dyn Clock>,
+    experiment: Option<Experiment>,
+    load_report: LoadReport,
 }
 
 impl Default for KnnService {
@@ -58,8 +120,30 @@ impl KnnService {
     pub fn new() -> Self {
         KnnService {
             embedding_registry: None,
+            default_products: HashMap::new(),
             default_model: None,
             models: HashMap::new(),
+            clock: Arc::<SystemClock>::default(),
+            experiment: None,
+            load_report: LoadReport::default(),
+        }
+    }
+
+    /// Replaces the clock giving the current time of requests without reference timestamp.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Sets the reference timestamp of the request from the clock when missing, so that
+    /// the filters and the model use the same time.
+    fn with_reference_timestamp(&self, options: &ComputeOptions) -> ComputeOptions {
+        ComputeOptions {
+            reference_timestamp: Some(
+                options
+                    .reference_timestamp
+                    .unwrap_or_else(|| self.clock.now()),
+            ),
+            ..options.clone()
         }
     }
 
@@ -71,6 +155,50 @@ impl KnnService {
         }
     }
 
+    /// Returns up to `max_count` labels of `partner_id` in ascending order, starting right after
+    /// the `after` cursor. The second value is the cursor of the next page, if any.
+    pub fn list_labels_page(
+        &self,
+        partner_id: i32,
+        after: Option<i64>,
+        max_count: usize,
+    ) -> Result<(Vec<i64>, Option<i64>), KnnError> {
+        let labels = self.sorted_labels(partner_id)?;
+        Ok(labels_page(labels, after, max_count))
+    }
+
+    /// Returns a random sample of up to `max_count` labels of `partner_id`.
+    /// The sample only depends on the loaded labels and on `seed`.
+    pub fn sample_labels(
+        &self,
+        partner_id: i32,
+        max_count: usize,
+        seed: u64,
+    ) -> Result<Vec<i64>, KnnError> {
+        let labels = self.sorted_labels(partner_id)?;
+        let mut rng = StdRng::seed_from_u64(seed);
+        Ok(labels
+            .choose_multiple(&mut rng, max_count)
+            .copied()
+            .collect())
+    }
+
+    fn sorted_labels(&self, partner_id: i32) -> Result<&[i64], KnnError> {
+        if let Some(emr) = self.embedding_registry.as_ref() {
+            emr.sorted_labels(partner_id)
+        } else {
+            Err(KnnError::IndexNotLoaded)
+        }
+    }
+
+    pub fn list_indices(&self) -> Result<Vec<IndexStats>, KnnError> {
+        if let Some(emr) = self.embedding_registry.as_ref() {
+            Ok(emr.list_indices())
+        } else {
+            Err(KnnError::IndexNotLoaded)
+        }
+    }
+
     pub fn get_item(&self, partner_id: i32, label: i64) -> Result<Option<Vec<f32>>, KnnError> {
         if let Some(emr) = self.embedding_registry.as_ref() {
             emr.fetch_item(partner_id, label)
@@ -79,65 +207,242 @@ impl KnnService {
         }
     }
 
-    pub fn load_index<P: AsRef<Path>>(&mut self, indices_path: P) -> Result<(), KnnError> {
+    pub fn get_norm(&self, partner_id: i32, label: i64) -> Result<Option<f32>, KnnError> {
+        if let Some(emr) = self.embedding_registry.as_ref() {
+            emr.fetch_norm(partner_id, label)
+        } else {
+            Err(KnnError::IndexNotLoaded)
+        }
+    }
+
+    pub fn load_index<P: AsRef<Path>>(
+        &mut self,
+        indices_path: P,
+        options: &LoadOptions,
+    ) -> Result<(), KnnError> {
         info!(
             "KnnService: Starting load from {}",
             indices_path.as_ref().display(),
         );
-        let map = Loader::load_index_folder(indices_path)?;
+        let (map, load_report) = Loader::load_index_folder(indices_path.as_ref(), options)?;
+        self.load_report = load_report;
+        self.default_products = Loader::load_default_products(indices_path.as_ref())?;
         if let Some((_, i)) = map.iter().next() {
             let dim = i.dimension();
             let registry = EmbeddingRegistry::new(dim, map);
-            self.embedding_registry.replace(registry);
+            self.embedding_registry.replace(Arc::new(registry));
         }
 
         info!("KnnService: Load done");
         Ok(())
     }
 
+    /// Chunks skipped by the last index load.
+    pub fn load_report(&self) -> &LoadReport {
+        &self.load_report
+    }
+
     pub fn load_model<P: AsRef<Path>>(
         &mut self,
         model: Model,
-        tf_model: Option<P>,
+        model_path: Option<P>,
     ) -> Result<(), KnnError> {
         info!("KnnService: Starting model load of {}", model.name);
-        let computer: Box<dyn UserEmbeddingComputer> = match model.model_type {
-            ModelType::Average => Box::<AverageComputer>::default(),
+        let computer: Arc<dyn UserEmbeddingComputer> = match model.model_type {
+            ModelType::Average => Arc::<AverageComputer>::default(),
+            ModelType::WeightedAverage => Arc::<WeightedAverageComputer>::default(),
             ModelType::Tensorflow => {
-                if let Some(mp) = tf_model {
-                    Box::new(KnnTf::load_model(mp)?)
+                if let Some(mp) = model_path {
+                    let registry = self
+                        .embedding_registry
+                        .as_ref()
+                        .ok_or(KnnError::IndexNotLoaded)?;
+                    Arc::new(KnnTfPool::load_model(mp, &model, registry.dim)?)
                 } else {
                     return Err(KnnError::InvalidPath);
                 }
             }
-            ModelType::XLA => unimplemented!(),
+            ModelType::Onnx => {
+                if let Some(mp) = model_path {
+                    load_onnx_model(mp)?
+                } else {
+                    return Err(KnnError::InvalidPath);
+                }
+            }
+            ModelType::XLA => {
+                return Err(KnnError::UnsupportedModelType(format!(
+                    "{:?}",
+                    model.model_type
+                )))
+            }
         };
-        self.models.insert(model.name.clone(), computer);
         if model.is_default {
             self.default_model = Some(model.name.clone())
         }
+        self.models.insert(model.name.clone(), (model, computer));
         info!("KnnService: Model load done");
         Ok(())
     }
 
+    pub fn set_default_model(&mut self, model_name: &str) -> Result<(), KnnError> {
+        if !self.models.contains_key(model_name) {
+            return Err(KnnError::ModelNotFound(model_name.to_string()));
+        }
+        self.default_model = Some(model_name.to_string());
+        Ok(())
+    }
+
+    pub fn default_model(&self) -> Option<&str> {
+        self.default_model.as_deref()
+    }
+
+    pub fn list_models(&self) -> Vec<String> {
+        let mut models: Vec<String> = self.models.keys().cloned().collect();
+        models.sort();
+        models
+    }
+
+    /// Splits the requests without model between the variants of `experiment`,
+    /// which must all be loaded.
+    pub fn set_experiment(&mut self, experiment: Option<Experiment>) -> Result<(), KnnError> {
+        if let Some(experiment) = &experiment {
+            experiment.validate()?;
+            if let Some(variant) = experiment
+                .variants
+                .iter()
+                .find(|v| !self.models.contains_key(&v.model))
+            {
+                return Err(KnnError::ModelNotFound(variant.model.clone()));
+            }
+        }
+        self.experiment = experiment;
+        Ok(())
+    }
+
+    pub fn experiment(&self) -> Option<&Experiment> {
+        self.experiment.as_ref()
+    }
+
+    /// Returns the model serving a request and the experiment which picked it, if any.
+    /// The requested model wins, then the variant of `routing_key` when an experiment runs,
+    /// then the default model. Requests without routing key are not part of the experiment.
+    pub fn select_model(
+        &self,
+        model: Option<String>,
+        routing_key: Option<&[u8]>,
+    ) -> (Option<String>, Option<&str>) {
+        match (model, self.experiment.as_ref(), routing_key) {
+            (Some(model), _, _) => (Some(model), None),
+            (None, Some(experiment), Some(routing_key)) => (
+                Some(experiment.variant(routing_key).model.clone()),
+                Some(experiment.name.as_str()),
+            ),
+            (None, _, _) => (self.default_model.clone(), None),
+        }
+    }
+
+    /// Configuration of the loaded models, sorted by name.
+    pub fn models(&self) -> Vec<&Model> {
+        let mut models: Vec<&Model> = self.models.values().map(|(model, _)| model).collect();
+        models.sort_by(|a, b| a.name.cmp(&b.name));
+        models
+    }
+
+    fn get_computer(
+        &self,
+        model: Option<String>,
+    ) -> Result<(&EmbeddingRegistry, &dyn UserEmbeddingComputer), KnnError> {
+        let model_name = model
+            .or(self.default_model.clone())
+            .ok_or(KnnError::ModelMissing)?;
+        let emr = self
+            .embedding_registry
+            .as_deref()
+            .ok_or(KnnError::IndexNotLoaded)?;
+        let (_, computer) = self
+            .models
+            .get(&model_name)
+            .ok_or(KnnError::ModelNotFound(model_name))?;
+        Ok((emr, computer.as_ref()))
+    }
+
     fn compute_user_vector(
         &self,
         model: Option<String>,
         user_events: &[UserEvent],
+        options: &ComputeOptions,
     ) -> Result<EmbeddingResult, KnnError> {
-        let model_name = model.or(self.default_model.clone());
-        if let Some(model_name) = model_name {
-            if let Some(emr) = self.embedding_registry.as_ref() {
-                self.models
-                    .get(&model_name)
-                    .ok_or(KnnError::ModelNotFound(model_name))
-                    .and_then(|m| m.compute_user_vector(emr, user_events))
-                    .map_err(From::from)
-            } else {
-                Err(KnnError::IndexNotLoaded)
+        let (emr, computer) = self.get_computer(model)?;
+        let options = self.with_reference_timestamp(options);
+        let user_events = options.filter_events(user_events, options.now());
+        computer.compute_user_vector(emr, &user_events, &options)
+    }
+
+    fn compute_user_vectors(
+        &self,
+        model: Option<String>,
+        timelines: &[Vec<UserEvent>],
+        options: &ComputeOptions,
+    ) -> Result<Vec<EmbeddingResult>, KnnError> {
+        let (emr, computer) = self.get_computer(model)?;
+        let options = self.with_reference_timestamp(options);
+        let now = options.now();
+        let timelines: Vec<Vec<UserEvent>> = timelines
+            .iter()
+            .map(|user_events| options.filter_events(user_events, now))
+            .collect();
+        computer.compute_user_vectors(emr, &timelines, &options)
+    }
+
+    /// Searches the user embedding in `query_index` or, when no event of the user is known and
+    /// `allow_zero_user_embedding` is set, returns the default products of the index if any,
+    /// falling back to a search with the zero vector.
+    ///
+    /// Default products have their rank as distance and `1 / (rank + 1)` as score, which only
+    /// orders the products of one index. Their dot product with the zero user embedding is 0.
+    fn search_index(
+        &self,
+        emr: &EmbeddingRegistry,
+        query_index: i32,
+        user_vector: &EmbeddingResult,
+        k: usize,
+        options: &ComputeOptions,
+    ) -> Result<Vec<IndexResult>, KnnError> {
+        let mut embedding = user_vector.user_embedding.as_slice();
+        let zero_embedding;
+        if user_vector.user_event_used_count == 0 {
+            if !options.allow_zero_user_embedding {
+                return Ok(vec![]);
             }
+            if let Some(products) = self.default_products.get(&query_index) {
+                let index = emr.embeddings.get(&query_index);
+                return products
+                    .iter()
+                    .take(k)
+                    .enumerate()
+                    .map(|(rank, label)| {
+                        let norm = match index {
+                            Some(index) => index.get_norm(*label)?.unwrap_or(0f32),
+                            None => 0f32,
+                        };
+                        Ok(IndexResult {
+                            label: *label,
+                            distance: rank as f32,
+                            score: 1f32 / (rank + 1) as f32,
+                            dotproduct: 0f32,
+                            squared_l2_norm: norm * norm,
+                        })
+                    })
+                    .collect();
+            }
+            zero_embedding = vec![0f32; emr.dim];
+            embedding = zero_embedding.as_slice();
+        }
+
+        if let Some(index) = emr.embeddings.get(&query_index) {
+            index.search(embedding, k)
         } else {
-            Err(KnnError::ModelMissing)
+            Ok(vec![])
         }
     }
 
@@ -147,21 +452,190 @@ impl KnnService {
         query_index: i32,
         k: usize,
         model: Option<String>,
-    ) -> Result<Vec<IndexResult>, KnnError> {
-        let user_vector = self.compute_user_vector(model, user_events)?;
+        options: &ComputeOptions,
+    ) -> Result<KnnResult, KnnError> {
+        let user_vector = self.compute_user_vector(model, user_events, options)?;
 
-        if user_vector.user_event_used_count == 0 {
-            return Ok(vec![]);
+        if let Some(emr) = self.embedding_registry.as_ref() {
+            let products = self.search_index(emr, query_index, &user_vector, k, options)?;
+            Ok(KnnResult::new(products, &user_vector))
+        } else {
+            Err(KnnError::IndexNotLoaded)
         }
+    }
+
+    /// Computes the user embedding once and searches it in every index of `query_indices`
+    /// (or in every loaded index when empty). Results are merged into a single list of `k` items,
+    /// by score. When no event of the user is known, the default products and zero vector
+    /// scores of different indices aren't comparable, so the results are interleaved by rank.
+    pub fn get_closest_items_multi(
+        &self,
+        user_events: &[UserEvent],
+        query_indices: &[i32],
+        k: usize,
+        model: Option<String>,
+        options: &ComputeOptions,
+    ) -> Result<KnnResult, KnnError> {
+        let user_vector = self.compute_user_vector(model, user_events, options)?;
 
         if let Some(emr) = self.embedding_registry.as_ref() {
-            if let Some(index) = emr.embeddings.get(&query_index) {
-                index.search(&user_vector.user_embedding, k)
-            } else {
-                Ok(vec![])
+            let mut query_indices = query_indices.to_vec();
+            if query_indices.is_empty() {
+                query_indices = emr.embeddings.keys().copied().collect();
             }
+            query_indices.sort_unstable();
+            query_indices.dedup();
+
+            let mut results = vec![];
+            for query_index in query_indices {
+                results.push(self.search_index(emr, query_index, &user_vector, k, options)?);
+            }
+            let mut products = if user_vector.user_event_used_count == 0 {
+                interleave(results)
+            } else {
+                let mut products: Vec<IndexResult> = results.into_iter().flatten().collect();
+                products.sort();
+                products
+            };
+            products.truncate(k);
+            Ok(KnnResult::new(products, &user_vector))
         } else {
             Err(KnnError::IndexNotLoaded)
         }
     }
+
+    /// Computes the embeddings of several users in one model call and searches them
+    /// in `query_index` with a single index search. Returns one result per timeline.
+    pub fn get_closest_items_batch(
+        &self,
+        timelines: &[Vec<UserEvent>],
+        query_index: i32,
+        k: usize,
+        model: Option<String>,
+        options: &ComputeOptions,
+    ) -> Result<Vec<KnnResult>, KnnError> {
+        let user_vectors = self.compute_user_vectors(model, timelines, options)?;
+        let emr = self
+            .embedding_registry
+            .as_deref()
+            .ok_or(KnnError::IndexNotLoaded)?;
+        let index = emr.embeddings.get(&query_index);
+        let zero_embedding_search =
+            options.allow_zero_user_embedding && !self.default_products.contains_key(&query_index);
+
+        let mut products: Vec<Vec<IndexResult>> = Vec::with_capacity(user_vectors.len());
+        let mut queries = Vec::with_capacity(user_vectors.len() * emr.dim);
+        let mut searched = vec![];
+        for (i, user_vector) in user_vectors.iter().enumerate() {
+            let has_embedding = user_vector.user_event_used_count > 0;
+            if index.is_some() && (has_embedding || zero_embedding_search) {
+                if has_embedding {
+                    queries.extend_from_slice(&user_vector.user_embedding);
+                } else {
+                    queries.resize(queries.len() + emr.dim, 0f32);
+                }
+                searched.push(i);
+                products.push(vec![]);
+            } else {
+                products.push(self.search_index(emr, query_index, user_vector, k, options)?);
+            }
+        }
+
+        if let Some(index) = index {
+            let results = index.search_batch(&queries, searched.len(), k)?;
+            for (i, result) in searched.into_iter().zip(results) {
+                products[i] = result;
+            }
+        }
+
+        Ok(products
+            .into_iter()
+            .zip(user_vectors.iter())
+            .map(|(products, user_vector)| KnnResult::new(products, user_vector))
+            .collect())
+    }
+}
+
+/// Page of the sorted `labels` starting right after `after`, and the cursor of the next page.
+fn labels_page(labels: &[i64], after: Option<i64>, max_count: usize) -> (Vec<i64>, Option<i64>) {
+    let start = after.map_or(0, |a| labels.partition_point(|l| *l <= a));
+    let mut page: Vec<i64> = labels
+        .iter()
+        .skip(start)
+        .take(max_count.saturating_add(1))
+        .copied()
+        .collect();
+    let next = if page.len() > max_count {
+        page.truncate(max_count);
+        page.last().copied()
+    } else {
+        None
+    };
+    (page, next)
+}
+
+/// Merges ranked lists by taking the first item of each list, then the second one, and so on.
+fn interleave(lists: Vec<Vec<IndexResult>>) -> Vec<IndexResult> {
+    let mut iters: Vec<_> = lists.into_iter().map(|l| l.into_iter()).collect();
+    let mut merged = vec![];
+    loop {
+        let before = merged.len();
+        merged.extend(iters.iter_mut().filter_map(|i| i.next()));
+        if merged.len() == before {
+            return merged;
+        }
+    }
+}
+
+#[cfg(feature = "onnx")]
+fn load_onnx_model<P: AsRef<Path>>(
+    model_path: P,
+) -> Result<Arc<dyn UserEmbeddingComputer>, KnnError> {
+    Ok(Arc::new(crate::knn_onnx::KnnOnnx::load_model(model_path)?))
+}
+
+#[cfg(not(feature = "onnx"))]
+fn load_onnx_model<P: AsRef<Path>>(
+    _model_path: P,
+) -> Result<Arc<dyn UserEmbeddingComputer>, KnnError> {
+    Err(KnnError::UnsupportedModelType(
+        "Onnx (built without the onnx feature)".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_page_walks_all_labels() {
+        let labels = [-3, 1, 2, 5, 8];
+        assert_eq!(labels_page(&labels, None, 2), (vec![-3, 1], Some(1)));
+        assert_eq!(labels_page(&labels, Some(1), 2), (vec![2, 5], Some(5)));
+        assert_eq!(labels_page(&labels, Some(5), 2), (vec![8], None));
+    }
+
+    #[test]
+    fn labels_page_has_no_cursor_when_the_last_page_is_full() {
+        let labels = [1, 2, 3, 4];
+        assert_eq!(labels_page(&labels, Some(2), 2), (vec![3, 4], None));
+        assert_eq!(
+            labels_page(&labels, None, usize::MAX),
+            (vec![1, 2, 3, 4], None)
+        );
+    }
+
+    #[test]
+    fn labels_page_starts_after_a_missing_cursor() {
+        let labels = [1, 3, 5];
+        assert_eq!(labels_page(&labels, Some(2), 10), (vec![3, 5], None));
+        assert_eq!(labels_page(&labels, Some(0), 1), (vec![1], Some(1)));
+    }
+
+    #[test]
+    fn labels_page_is_empty_after_the_last_label() {
+        assert_eq!(labels_page(&[1, 2], Some(2), 10), (vec![], None));
+        assert_eq!(labels_page(&[1, 2], Some(i64::MAX), 10), (vec![], None));
+        assert_eq!(labels_page(&[], None, 10), (vec![], None));
+    }
 }
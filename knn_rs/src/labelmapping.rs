@@ -0,0 +1,272 @@
+use byteorder::{BigEndian, ByteOrder};
+use faiss::Idx;
+use memmap2::Mmap;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::KnnError;
+
+/// Product id to faiss id mapping of an index.
+pub enum LabelMapping {
+    Map(HashMap<i64, Idx>),
+    /// The (product id, faiss id) pairs of the mapping sorted by product id, as big endian i64s,
+    /// after a header with the size and modification time of the inverse mapping they come from.
+    /// They are read from the `_inverseMapping.sorted.array` file written next to the export on
+    /// its first load, and memory-mapped so that processes loading the same files share them.
+    Mmap(Mmap),
+}
+
+impl LabelMapping {
+    const ENTRY_SIZE: usize = 16;
+
+    /// Memory-maps the sorted version of the `_inverseMapping.array` file at `path`, the big
+    /// endian product ids in faiss id order. It is written first if it doesn't exist yet or was
+    /// sorted from another version of the file. When it can't be written, the mapping is kept
+    /// in memory.
+    pub fn load_mmap<P: AsRef<Path>>(path: P) -> Result<LabelMapping, KnnError> {
+        let path = path.as_ref();
+        let sorted_path = path.with_extension("sorted.array");
+        let source = LabelMapping::source_header(path)?;
+        if let Some(entries) = LabelMapping::map_sorted(&sorted_path, source)? {
+            return Ok(LabelMapping::Mmap(entries));
+        }
+        let entries = LabelMapping::sorted_entries(&std::fs::read(path)?);
+        if let Err(e) = LabelMapping::write_entries(&sorted_path, source, &entries) {
+            warn!(
+                "{} can't be written, keeping the mapping in memory: {}",
+                sorted_path.display(),
+                e
+            );
+        } else if let Some(entries) = LabelMapping::map_sorted(&sorted_path, source)? {
+            return Ok(LabelMapping::Mmap(entries));
+        }
+        let map = entries
+            .into_iter()
+            .map(|(label, id)| (label, Idx::new(id as u64)))
+            .collect();
+        Ok(LabelMapping::Map(map))
+    }
+
+    /// The size and modification time in nanoseconds of the inverse mapping, which identify
+    /// the version of the file a sorted mapping comes from.
+    fn source_header(path: &Path) -> Result<(i64, i64), KnnError> {
+        let metadata = std::fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as i64)
+            .unwrap_or_default();
+        Ok((metadata.len() as i64, modified))
+    }
+
+    /// Memory-maps the sorted mapping at `path` if it exists and comes from `source`.
+    fn map_sorted(path: &Path, source: (i64, i64)) -> Result<Option<Mmap>, KnnError> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // Safety: the sorted mappings are renamed into place once written and never modified
+        let entries = unsafe { Mmap::map(&file)? };
+        if entries.len() < LabelMapping::ENTRY_SIZE
+            || entries.len() % LabelMapping::ENTRY_SIZE != 0
+            || LabelMapping::entry_at(&entries, 0) != source
+        {
+            info!("{} is outdated, sorting the mapping again", path.display());
+            return Ok(None);
+        }
+        Ok(Some(entries))
+    }
+
+    /// The (product id, faiss id) pairs of the big endian product ids, sorted by product id.
+    /// Like `Map`, the last faiss id of a product id repeated in the mapping is kept.
+    fn sorted_entries(labels: &[u8]) -> Vec<(i64, i64)> {
+        let mut entries: Vec<(i64, i64)> = labels
+            .chunks_exact(8)
+            .enumerate()
+            .map(|(id, label)| (BigEndian::read_i64(label), id as i64))
+            .collect();
+        entries.sort_unstable();
+        entries.reverse();
+        entries.dedup_by_key(|(label, _)| *label);
+        entries.reverse();
+        entries
+    }
+
+    /// Writes to a temporary file renamed at the end, as several processes may load the
+    /// same export concurrently.
+    fn write_entries(
+        path: &Path,
+        source: (i64, i64),
+        entries: &[(i64, i64)],
+    ) -> std::io::Result<()> {
+        let mut buf = vec![0u8; (entries.len() + 1) * LabelMapping::ENTRY_SIZE];
+        for ((label, id), bytes) in std::iter::once(&source)
+            .chain(entries)
+            .zip(buf.chunks_exact_mut(LabelMapping::ENTRY_SIZE))
+        {
+            BigEndian::write_i64(&mut bytes[..8], *label);
+            BigEndian::write_i64(&mut bytes[8..], *id);
+        }
+        let tmp_path = PathBuf::from(format!("{}.{}.tmp", path.display(), std::process::id()));
+        std::fs::write(&tmp_path, buf)?;
+        std::fs::rename(tmp_path, path)
+    }
+
+    fn entry_at(entries: &Mmap, i: usize) -> (i64, i64) {
+        let bytes = &entries[i * LabelMapping::ENTRY_SIZE..(i + 1) * LabelMapping::ENTRY_SIZE];
+        (
+            BigEndian::read_i64(&bytes[..8]),
+            BigEndian::read_i64(&bytes[8..]),
+        )
+    }
+
+    /// Number of distinct product ids.
+    pub fn len(&self) -> usize {
+        match self {
+            LabelMapping::Map(map) => map.len(),
+            LabelMapping::Mmap(entries) => entries.len() / LabelMapping::ENTRY_SIZE - 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, label: i64) -> Option<Idx> {
+        match self {
+            LabelMapping::Map(map) => map.get(&label).copied(),
+            LabelMapping::Mmap(entries) => {
+                let (mut low, mut high) = (1, self.len() + 1);
+                while low < high {
+                    let mid = low + (high - low) / 2;
+                    let (mid_label, id) = LabelMapping::entry_at(entries, mid);
+                    match mid_label.cmp(&label) {
+                        Ordering::Less => low = mid + 1,
+                        Ordering::Greater => high = mid,
+                        Ordering::Equal => return Some(Idx::new(id as u64)),
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    pub fn labels(&self) -> Vec<i64> {
+        match self {
+            LabelMapping::Map(map) => map.keys().copied().collect(),
+            LabelMapping::Mmap(entries) => (1..=self.len())
+                .map(|i| LabelMapping::entry_at(entries, i).0)
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use tempdir::TempDir;
+
+    fn write_inverse_mapping(dir: &TempDir, labels: &[i64]) -> PathBuf {
+        let path = dir.path().join("0.1.0.True.index_inverseMapping.array");
+        let mut buf = vec![];
+        for label in labels {
+            buf.write_i64::<BigEndian>(*label).unwrap();
+        }
+        std::fs::write(&path, buf).unwrap();
+        path
+    }
+
+    fn map(labels: &[i64]) -> LabelMapping {
+        LabelMapping::Map(
+            labels
+                .iter()
+                .enumerate()
+                .map(|(id, label)| (*label, Idx::new(id as u64)))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn mmap_finds_the_faiss_id_of_unsorted_labels() {
+        let dir = TempDir::new("labelmapping").unwrap();
+        let labels = [42, -7, 13, i64::MAX, 0];
+        let mapping = LabelMapping::load_mmap(write_inverse_mapping(&dir, &labels)).unwrap();
+        assert!(matches!(mapping, LabelMapping::Mmap(_)));
+        for (id, label) in labels.iter().enumerate() {
+            assert_eq!(mapping.get(*label), Some(Idx::new(id as u64)));
+        }
+        assert_eq!(mapping.get(1), None);
+        assert_eq!(mapping.get(i64::MIN), None);
+        assert_eq!(mapping.labels(), vec![-7, 0, 13, 42, i64::MAX]);
+    }
+
+    #[test]
+    fn mmap_reuses_the_sorted_mapping() {
+        let dir = TempDir::new("labelmapping").unwrap();
+        let path = write_inverse_mapping(&dir, &[3, 1, 2]);
+        LabelMapping::load_mmap(&path).unwrap();
+        let sorted_path = path.with_extension("sorted.array");
+        let written = std::fs::read(&sorted_path).unwrap();
+        // An entry changed in place is served, so the sorted mapping wasn't written again
+        let mut changed = written.clone();
+        BigEndian::write_i64(&mut changed[24..32], 0);
+        std::fs::write(&sorted_path, &changed).unwrap();
+        let mapping = LabelMapping::load_mmap(&path).unwrap();
+        assert_eq!(mapping.get(1), Some(Idx::new(0)));
+        assert_eq!(mapping.len(), 3);
+    }
+
+    #[test]
+    fn mmap_sorts_a_changed_mapping_again() {
+        let dir = TempDir::new("labelmapping").unwrap();
+        let path = write_inverse_mapping(&dir, &[3, 1, 2]);
+        LabelMapping::load_mmap(&path).unwrap();
+
+        // Another size
+        write_inverse_mapping(&dir, &[7, 5]);
+        let mapping = LabelMapping::load_mmap(&path).unwrap();
+        assert_eq!(mapping.labels(), vec![5, 7]);
+        assert_eq!(mapping.get(5), Some(Idx::new(1)));
+
+        // Same size, another modification time
+        write_inverse_mapping(&dir, &[9, 8]);
+        let modified = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let mapping = LabelMapping::load_mmap(&path).unwrap();
+        assert_eq!(mapping.labels(), vec![8, 9]);
+        assert_eq!(mapping.get(8), Some(Idx::new(1)));
+        assert_eq!(mapping.get(5), None);
+    }
+
+    #[test]
+    fn mmap_and_map_agree_on_repeated_labels() {
+        let dir = TempDir::new("labelmapping").unwrap();
+        let labels = [5, 8, 5, 1, 8];
+        let mmap = LabelMapping::load_mmap(write_inverse_mapping(&dir, &labels)).unwrap();
+        let map = map(&labels);
+        assert_eq!(mmap.len(), 3);
+        assert_eq!(map.len(), 3);
+        for label in [1, 5, 8, 9] {
+            assert_eq!(mmap.get(label), map.get(label));
+        }
+    }
+
+    #[test]
+    fn empty_mappings_find_nothing() {
+        let dir = TempDir::new("labelmapping").unwrap();
+        let mmap = LabelMapping::load_mmap(write_inverse_mapping(&dir, &[])).unwrap();
+        assert!(mmap.is_empty());
+        assert_eq!(mmap.get(0), None);
+        assert!(map(&[]).is_empty());
+    }
+}
@@ -6,11 +6,16 @@ use tensorflow::Status;
 use thiserror::Error;
 
 pub mod embedding_computer;
+pub mod experiment;
+#[cfg(feature = "onnx")]
+pub mod knn_onnx;
 pub mod knn_tf;
 pub mod knncountry;
 pub mod knnindex;
 pub mod knnservice;
+pub mod labelmapping;
 pub mod loader;
+pub mod parquetloader;
 pub mod productindex;
 pub mod wrappedindex;
 
@@ -38,10 +43,32 @@ pub enum KnnError {
     SerdeError(#[from] serde_json::Error),
     #[error("Error in Faiss {0}")]
     FaissError(#[from] faiss::error::Error),
+    #[error("Error in Parquet {0}")]
+    ParquetError(#[from] parquet::errors::ParquetError),
+    #[error("Parquet file {0} is invalid: {1}")]
+    InvalidParquet(String, String),
     #[error("Error in TF model {0}")]
     TFError(String),
+    #[error("Error in ONNX model {0}")]
+    OnnxError(String),
+    #[error("Invalid model {0}: {1}")]
+    InvalidModel(String, String),
+    #[error("Invalid experiment {0}: {1}")]
+    InvalidExperiment(String, String),
+    #[error("Chunk {0} is inconsistent: {1}")]
+    InconsistentChunk(String, String),
+    #[error("Chunk {0} has dimension {1} while chunk {2} has dimension {3}")]
+    InconsistentDimension(String, usize, String, usize),
     #[error("Not country {0} can be found to insert Model. Please load the country first")]
     CountryNotFoundWhileLoadingModel(String),
+    #[error("Norms file {0} has {1} values while its index has {2} vectors")]
+    InvalidNorms(String, usize, usize),
+    #[error("Expected an embedding of dimension {0}, got {1}")]
+    InvalidDimension(usize, usize),
+    #[error("Model type {0} is not supported yet")]
+    UnsupportedModelType(String),
+    #[error("Model {0} has a path but no version")]
+    ModelVersionMissing(String),
 }
 
 impl From<tensorflow::Status> for KnnError {
@@ -50,22 +77,107 @@ impl From<tensorflow::Status> for KnnError {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg(feature = "onnx")]
+impl From<ort::Error> for KnnError {
+    fn from(error: ort::Error) -> Self {
+        KnnError::OnnxError(error.to_string())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Distance {
     Euclidean,
     Angular,
     InnerProduct,
 }
 
+impl Distance {
+    /// Converts a faiss distance into a score where higher is better:
+    /// `1 / (1 + d)` with `d` the euclidean distance, the cosine similarity for angular
+    /// (vectors are expected to be normalized) and the dot product for inner product.
+    pub fn score(&self, distance: f32, metric_type: faiss::MetricType) -> f32 {
+        match (self, metric_type) {
+            (Distance::Euclidean, faiss::MetricType::L2) => {
+                1f32 / (1f32 + distance.max(0f32).sqrt())
+            }
+            (Distance::Euclidean, _) => distance,
+            (Distance::Angular, faiss::MetricType::L2) => 1f32 - distance / 2f32,
+            (Distance::Angular, _) => distance,
+            (Distance::InnerProduct, _) => distance,
+        }
+    }
+}
+
 impl FromStr for Distance {
     type Err = KnnError;
 
     fn from_str(value: &str) -> Result<Self, Self::Err> {
         match value.to_lowercase().as_ref() {
-            "euclidean" => Ok(Distance::Euclidean),
-            "angular" => Ok(Distance::Angular),
-            "dotproduct" => Ok(Distance::InnerProduct),
+            "euclidean" | "l2" => Ok(Distance::Euclidean),
+            "angular" | "cosine" => Ok(Distance::Angular),
+            "dotproduct" | "innerproduct" | "inner_product" => Ok(Distance::InnerProduct),
             _ => Err(KnnError::UnknownDistance(value.to_string())),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use faiss::MetricType;
+
+    #[test]
+    fn euclidean_scores_decrease_with_the_distance() {
+        // faiss returns squared L2 distances
+        assert_eq!(Distance::Euclidean.score(0f32, MetricType::L2), 1f32);
+        assert_eq!(Distance::Euclidean.score(9f32, MetricType::L2), 0.25f32);
+        assert!(
+            Distance::Euclidean.score(1f32, MetricType::L2)
+                > Distance::Euclidean.score(4f32, MetricType::L2)
+        );
+        // Rounding may give slightly negative distances
+        assert_eq!(Distance::Euclidean.score(-1e-6, MetricType::L2), 1f32);
+    }
+
+    #[test]
+    fn angular_scores_are_cosine_similarities() {
+        // Squared L2 distances between normalized vectors
+        assert_eq!(Distance::Angular.score(0f32, MetricType::L2), 1f32);
+        assert_eq!(Distance::Angular.score(2f32, MetricType::L2), 0f32);
+        assert_eq!(Distance::Angular.score(4f32, MetricType::L2), -1f32);
+        assert_eq!(
+            Distance::Angular.score(0.5f32, MetricType::InnerProduct),
+            0.5f32
+        );
+    }
+
+    #[test]
+    fn inner_product_scores_are_the_distances() {
+        assert_eq!(
+            Distance::InnerProduct.score(3.5f32, MetricType::InnerProduct),
+            3.5f32
+        );
+        assert_eq!(
+            Distance::InnerProduct.score(-2f32, MetricType::InnerProduct),
+            -2f32
+        );
+    }
+
+    #[test]
+    fn distances_are_parsed_case_insensitively() {
+        for (value, distance) in [
+            ("euclidean", Distance::Euclidean),
+            ("L2", Distance::Euclidean),
+            ("Angular", Distance::Angular),
+            ("cosine", Distance::Angular),
+            ("dotProduct", Distance::InnerProduct),
+            ("INNER_PRODUCT", Distance::InnerProduct),
+        ] {
+            assert_eq!(Distance::from_str(value).unwrap(), distance);
+        }
+        assert!(matches!(
+            Distance::from_str("manhattan"),
+            Err(KnnError::UnknownDistance(v)) if v == "manhattan"
+        ));
+    }
+}
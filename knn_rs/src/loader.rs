@@ -1,12 +1,126 @@
 use byteorder::{BigEndian, ReadBytesExt};
-use faiss::Idx;
+use faiss::index::io::read_index_with_flags;
+use faiss::index::io_flags::IoFlags;
+use faiss::index::IndexImpl;
+use faiss::{Idx, Index};
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::io::{BufReader, ErrorKind};
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
 
 use crate::knnindex::{KnnIndex, Metadata};
+use crate::labelmapping::LabelMapping;
+use crate::parquetloader::ParquetOptions;
+use crate::productindex::ProductIndex;
 use crate::wrappedindex::WrappedIndex;
-use crate::KnnError;
+use crate::{Distance, KnnError};
+
+/// How the indices of a folder are loaded.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoadOptions {
+    /// Memory-maps the faiss indices when their type supports it and the label mappings,
+    /// instead of reading them in memory. The page cache is then shared by the processes
+    /// serving the same files.
+    #[serde(default)]
+    pub mmap: bool,
+    /// Number of chunks loaded in parallel, the number of cores when not set.
+    pub threads: Option<usize>,
+    /// Number of countries loaded in parallel, 1 when not set. Up to
+    /// `threads * parallel_countries` threads are used.
+    pub parallel_countries: Option<usize>,
+    #[serde(default)]
+    pub policy: LoadPolicy,
+    /// Builds the indices from the Parquet files of the folder instead of loading
+    /// the faiss index files listed in `metadata.json`.
+    #[serde(default)]
+    pub parquet: Option<ParquetOptions>,
+}
+
+/// What to do with the chunks which fail to load.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LoadPolicy {
+    /// The load fails.
+    #[default]
+    Strict,
+    /// The chunk is skipped, the other chunks of its partner are served.
+    SkipChunk,
+    /// The whole partner is skipped.
+    SkipPartner,
+}
+
+/// A chunk which failed to load.
+#[derive(Debug, Clone)]
+pub struct SkippedChunk {
+    pub partner_id: i32,
+    pub chunk_id: i32,
+    pub is_recommendable: bool,
+    pub reason: String,
+}
+
+/// Outcome of the load of an index folder.
+#[derive(Debug, Clone, Default)]
+pub struct LoadReport {
+    pub loaded_chunks: usize,
+    pub skipped_chunks: Vec<SkippedChunk>,
+    /// Partners not served because of a skipped chunk, with `LoadPolicy::SkipPartner`.
+    pub skipped_partners: Vec<i32>,
+}
+
+impl LoadOptions {
+    pub fn threads(&self) -> usize {
+        self.threads
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            })
+            .max(1)
+    }
+
+    pub fn parallel_countries(&self) -> usize {
+        self.parallel_countries.unwrap_or(1).max(1)
+    }
+}
+
+/// Applies `f` to the items with at most `threads` threads, and returns the results
+/// in the order of the items.
+pub(crate) fn parallel_map<T, R, F>(items: &[T], threads: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let workers = threads.clamp(1, items.len().max(1));
+    let mut results: Vec<(usize, R)> = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut results = vec![];
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        if i >= items.len() {
+                            break;
+                        }
+                        results.push((i, f(&items[i])));
+                    }
+                    results
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().expect("loading thread panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, r)| r).collect()
+}
 
 pub enum Loader {}
 
@@ -59,45 +173,250 @@ impl Loader {
         Ok(map)
     }
 
-    fn load_index<P: AsRef<Path>>(path: P, metadata: &Metadata) -> Result<WrappedIndex, KnnError> {
-        let path = path.as_ref();
+    fn read_index(path: String, options: &LoadOptions) -> Result<IndexImpl, KnnError> {
+        if options.mmap {
+            match read_index_with_flags(&path, IoFlags::MEM_MAP) {
+                Ok(index) => return Ok(index),
+                Err(e) => warn!("{} can't be memory-mapped, reading it: {}", path, e),
+            }
+        }
+        Ok(faiss::read_index(path)?)
+    }
+
+    fn index_filename(metadata: &Metadata) -> String {
         let f_boolean = if metadata.is_recommendable {
             "True"
         } else {
             "False"
         };
-        let index_filename = format!(
+        format!(
             "{}.{}.{}.{}.index",
             metadata.country, metadata.partner_id, metadata.chunk_id, f_boolean
-        );
+        )
+    }
+
+    /// Size in bytes of the index, mapping and norms files of a chunk.
+    fn chunk_size(path: &Path, metadata: &Metadata) -> u64 {
+        let index_filename = Loader::index_filename(metadata);
+        [
+            index_filename.clone(),
+            format!("{}_inverseMapping.array", index_filename),
+            format!("{}_embeddingNorms.array", index_filename),
+        ]
+        .iter()
+        .filter_map(|f| std::fs::metadata(path.join("indices").join(f)).ok())
+        .map(|m| m.len())
+        .sum()
+    }
+
+    fn load_index<P: AsRef<Path>>(
+        path: P,
+        metadata: &Metadata,
+        options: &LoadOptions,
+    ) -> Result<WrappedIndex, KnnError> {
+        let path = path.as_ref();
+        let index_filename = Loader::index_filename(metadata);
         let norm_filename = format!("{}_embeddingNorms.array", index_filename);
         let mapping_filename = format!("{}_inverseMapping.array", index_filename);
-        let local_path = path.join("indices").join(index_filename);
+        let local_path = path.join("indices").join(&index_filename);
 
         let local_path_str = local_path
             .into_os_string()
             .into_string()
             .map_err(|_| KnnError::InvalidPath)?;
-        let index = faiss::read_index(local_path_str)?;
+        let index = Loader::read_index(local_path_str, options)?;
 
-        let mapping = Loader::load_mapping(path.join("indices").join(mapping_filename.clone()))?;
-        let norm = Loader::load_embedding_norms(path.join("indices").join(mapping_filename))?;
-        Ok(WrappedIndex::new(Box::new(index), mapping, norm))
+        let mapping_path = path.join("indices").join(mapping_filename);
+        let mapping = if options.mmap {
+            LabelMapping::load_mmap(mapping_path)?
+        } else {
+            LabelMapping::Map(Loader::load_mapping(mapping_path)?)
+        };
+        let norm = Loader::load_embedding_norms(path.join("indices").join(&norm_filename))?;
+        let ntotal = index.ntotal() as usize;
+        let inconsistent =
+            |reason: String| Err(KnnError::InconsistentChunk(index_filename.clone(), reason));
+        if ntotal != metadata.count {
+            return inconsistent(format!(
+                "index has {} vectors while metadata declares {}",
+                ntotal, metadata.count
+            ));
+        }
+        if index.d() as usize != metadata.dimension {
+            return inconsistent(format!(
+                "index has dimension {} while metadata declares {}",
+                index.d(),
+                metadata.dimension
+            ));
+        }
+        if mapping.len() != ntotal {
+            return inconsistent(format!(
+                "mapping has {} distinct labels while index has {} vectors",
+                mapping.len(),
+                ntotal
+            ));
+        }
+        if norm.len() != ntotal {
+            return Err(KnnError::InvalidNorms(norm_filename, norm.len(), ntotal));
+        }
+        let metric = Distance::from_str(&metadata.metric).unwrap_or_else(|_| {
+            warn!(
+                "Unknown metric {} of chunk {}, using euclidean",
+                metadata.metric, index_filename
+            );
+            Distance::Euclidean
+        });
+        Ok(WrappedIndex::new(Box::new(index), mapping, norm, metric))
     }
 
-    pub fn load_index_folder<P>(path: P) -> Result<HashMap<i32, KnnIndex>, KnnError>
+    /// Loads the optional `default_products.json` of the folder, a map from index id to
+    /// the products returned when the user embedding can't be computed.
+    pub fn load_default_products<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<HashMap<i32, Vec<i64>>, KnnError> {
+        let default_products_path = path.as_ref().join("default_products.json");
+        if !default_products_path.exists() {
+            return Ok(HashMap::new());
+        }
+        info!(
+            "Loading default products from {}",
+            default_products_path.display()
+        );
+        let fs = std::fs::File::open(default_products_path)?;
+        let default_products = serde_json::from_reader(fs)?;
+        Ok(default_products)
+    }
+
+    /// Handles the chunks which failed to load according to the policy. `chunks` holds the
+    /// (partner id, chunk id, is recommendable) of the chunks, in the order of `loaded`.
+    /// Returns the (partner id, position) and index of the chunks to serve.
+    pub(crate) fn apply_policy<I>(
+        path: &Path,
+        policy: LoadPolicy,
+        chunks: impl Iterator<Item = (i32, i32, bool)>,
+        loaded: Vec<Result<I, KnnError>>,
+    ) -> Result<(Vec<((i32, usize), I)>, LoadReport), KnnError> {
+        let mut report = LoadReport::default();
+        let mut served = Vec::with_capacity(loaded.len());
+        for (position, ((partner_id, chunk_id, is_recommendable), index)) in
+            chunks.zip(loaded).enumerate()
+        {
+            match index {
+                Ok(index) => served.push(((partner_id, position), index)),
+                Err(e) if policy == LoadPolicy::Strict => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "Skipping chunk {}/{} of {}: {}",
+                        partner_id,
+                        chunk_id,
+                        path.display(),
+                        e
+                    );
+                    report.skipped_chunks.push(SkippedChunk {
+                        partner_id,
+                        chunk_id,
+                        is_recommendable,
+                        reason: e.to_string(),
+                    });
+                }
+            }
+        }
+        if policy == LoadPolicy::SkipPartner {
+            let mut skipped_partners: Vec<i32> =
+                report.skipped_chunks.iter().map(|c| c.partner_id).collect();
+            skipped_partners.sort_unstable();
+            skipped_partners.dedup();
+            for partner_id in skipped_partners.iter() {
+                warn!("Skipping partner {} of {}", partner_id, path.display());
+            }
+            served
+                .retain(|((partner_id, _), _)| skipped_partners.binary_search(partner_id).is_err());
+            report.skipped_partners = skipped_partners;
+        }
+        report.loaded_chunks = served.len();
+        Ok((served, report))
+    }
+
+    /// Loads the chunks of the folder with `LoadOptions::threads` threads, logging the progress
+    /// and the loading time of each chunk. Chunks failing to load are handled according to
+    /// `LoadOptions::policy` and listed in the report. With `LoadOptions::parquet`, the indices
+    /// are built from the Parquet files of the folder instead.
+    pub fn load_index_folder<P>(
+        path: P,
+        options: &LoadOptions,
+    ) -> Result<(HashMap<i32, KnnIndex>, LoadReport), KnnError>
     where
         P: AsRef<Path>,
     {
-        info!("Starting to load {}", path.as_ref().display());
-        let metadata_path = path.as_ref().join("metadata.json");
+        let path = path.as_ref();
+        if let Some(parquet_options) = &options.parquet {
+            return Loader::load_parquet_folder(path, parquet_options, options);
+        }
+        let start = Instant::now();
+        info!("Starting to load {}", path.display());
+        let metadata_path = path.join("metadata.json");
         let fs = std::fs::File::open(metadata_path)?;
         let metadatas: Vec<Metadata> = serde_json::from_reader(fs)?;
-        let mut indices: HashMap<i32, KnnIndex> = HashMap::new();
 
-        for m in metadatas {
-            debug!("Loading chunk {}/{}", m.partner_id, m.chunk_id);
-            let index = Loader::load_index(path.as_ref(), &m)?;
+        let total = metadatas.len();
+        let total_bytes: u64 = metadatas.iter().map(|m| Loader::chunk_size(path, m)).sum();
+        let done = AtomicUsize::new(0);
+        let done_bytes = AtomicU64::new(0);
+        let loaded = parallel_map(&metadatas, options.threads(), |m| -> Result<_, KnnError> {
+            let chunk_start = Instant::now();
+            let index = Loader::load_index(path, m, options)?;
+            let size = Loader::chunk_size(path, m);
+            let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+            let done_bytes = done_bytes.fetch_add(size, Ordering::Relaxed) + size;
+            debug!(
+                "Loaded chunk {}/{} ({} bytes) in {:?}",
+                m.partner_id,
+                m.chunk_id,
+                size,
+                chunk_start.elapsed()
+            );
+            // Every 10%
+            if done * 10 / total != (done - 1) * 10 / total {
+                info!(
+                    "Loaded {}/{} chunks, {}/{} MB of {}",
+                    done,
+                    total,
+                    done_bytes >> 20,
+                    total_bytes >> 20,
+                    path.display()
+                );
+            }
+            Ok(index)
+        });
+
+        let (chunks, report) = Loader::apply_policy(
+            path,
+            options.policy,
+            metadatas
+                .iter()
+                .map(|m| (m.partner_id, m.chunk_id, m.is_recommendable)),
+            loaded,
+        )?;
+        let chunks: Vec<(&Metadata, WrappedIndex)> = chunks
+            .into_iter()
+            .map(|((_, chunk), index)| (&metadatas[chunk], index))
+            .collect();
+
+        // The registry has a single dimension, used to compute the user embeddings
+        if let Some((first, first_index)) = chunks.first() {
+            let dim = first_index.dimension();
+            if let Some((m, index)) = chunks.iter().find(|(_, i)| i.dimension() != dim) {
+                return Err(KnnError::InconsistentDimension(
+                    Loader::index_filename(m),
+                    index.dimension(),
+                    Loader::index_filename(first),
+                    dim,
+                ));
+            }
+        }
+
+        let mut indices: HashMap<i32, KnnIndex> = HashMap::new();
+        for (m, index) in chunks {
             let ki = indices.entry(m.partner_id).or_default();
             if m.is_recommendable {
                 ki.add_reco_index(index)
@@ -105,7 +424,105 @@ impl Loader {
                 ki.add_non_reco_index(index)
             }
         }
-        info!("Load done");
-        Ok(indices)
+        info!(
+            "Load of {} done in {:?}, {} chunks loaded, {} skipped",
+            path.display(),
+            start.elapsed(),
+            report.loaded_chunks,
+            report.skipped_chunks.len()
+        );
+        Ok((indices, report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Partner 1 has a failing reco chunk and a failing non reco chunk
+    const CHUNKS: [(i32, i32, bool); 5] = [
+        (1, 0, true),
+        (1, 1, true),
+        (1, 2, false),
+        (2, 0, true),
+        (3, 0, false),
+    ];
+
+    fn loaded() -> Vec<Result<&'static str, KnnError>> {
+        let failed = |chunk: &str| -> Result<&'static str, KnnError> {
+            Err(KnnError::InconsistentChunk(
+                chunk.to_string(),
+                "index has 2 vectors while metadata declares 3".to_string(),
+            ))
+        };
+        vec![
+            Ok("1/0"),
+            failed("1/1"),
+            failed("1/2"),
+            Ok("2/0"),
+            Ok("3/0"),
+        ]
+    }
+
+    fn apply_policy(
+        policy: LoadPolicy,
+    ) -> Result<(Vec<((i32, usize), &'static str)>, LoadReport), KnnError> {
+        Loader::apply_policy(Path::new("index"), policy, CHUNKS.into_iter(), loaded())
+    }
+
+    fn skipped_chunks(report: &LoadReport) -> Vec<(i32, i32, bool)> {
+        report
+            .skipped_chunks
+            .iter()
+            .map(|c| (c.partner_id, c.chunk_id, c.is_recommendable))
+            .collect()
+    }
+
+    #[test]
+    fn strict_policy_fails_on_the_first_failing_chunk() {
+        match apply_policy(LoadPolicy::Strict) {
+            Err(KnnError::InconsistentChunk(chunk, _)) => assert_eq!(chunk, "1/1"),
+            other => panic!("unexpected result {:?}", other.map(|(served, _)| served)),
+        }
+    }
+
+    #[test]
+    fn skip_chunk_policy_serves_the_other_chunks_of_the_partner() {
+        let (served, report) = apply_policy(LoadPolicy::SkipChunk).unwrap();
+        assert_eq!(
+            served,
+            vec![((1, 0), "1/0"), ((2, 3), "2/0"), ((3, 4), "3/0")]
+        );
+        assert_eq!(report.loaded_chunks, 3);
+        assert_eq!(skipped_chunks(&report), vec![(1, 1, true), (1, 2, false)]);
+        assert!(report.skipped_chunks[0].reason.contains("1/1"));
+        assert!(report.skipped_partners.is_empty());
+    }
+
+    #[test]
+    fn skip_partner_policy_skips_every_chunk_of_the_partner() {
+        let (served, report) = apply_policy(LoadPolicy::SkipPartner).unwrap();
+        assert_eq!(served, vec![((2, 3), "2/0"), ((3, 4), "3/0")]);
+        assert_eq!(report.loaded_chunks, 2);
+        assert_eq!(skipped_chunks(&report), vec![(1, 1, true), (1, 2, false)]);
+        assert_eq!(report.skipped_partners, vec![1]);
+    }
+
+    #[test]
+    fn policies_serve_every_chunk_when_none_fails() {
+        for policy in [
+            LoadPolicy::Strict,
+            LoadPolicy::SkipChunk,
+            LoadPolicy::SkipPartner,
+        ] {
+            let loaded = vec![Ok("1/0"), Ok("2/0")];
+            let chunks = [(1, 0, true), (2, 0, false)].into_iter();
+            let (served, report) =
+                Loader::apply_policy(Path::new("index"), policy, chunks, loaded).unwrap();
+            assert_eq!(served, vec![((1, 0), "1/0"), ((2, 1), "2/0")]);
+            assert_eq!(report.loaded_chunks, 2);
+            assert!(report.skipped_chunks.is_empty());
+            assert!(report.skipped_partners.is_empty());
+        }
     }
 }
@@ -0,0 +1,399 @@
+use faiss::{Idx, Index, MetricType};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::{Field, RowAccessor};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::knnindex::KnnIndex;
+use crate::labelmapping::LabelMapping;
+use crate::loader::{parallel_map, LoadOptions, LoadReport, Loader};
+use crate::productindex::squared_l2_norm;
+use crate::wrappedindex::WrappedIndex;
+use crate::{Distance, KnnError};
+
+/// Builds the indices from Parquet files instead of reading faiss index exports.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParquetOptions {
+    /// faiss `index_factory` description of the indices, e.g. `Flat` or `IVF1024,PQ32`.
+    /// The vectors are identified by their position, so the description can't contain
+    /// an `IDMap`.
+    pub factory: String,
+    /// Distance of the indices: euclidean, angular or inner_product. Angular vectors
+    /// are normalized before being added.
+    pub metric: String,
+    /// Dimension of the embeddings, the one of the first row of the first file when not set.
+    pub dimension: Option<usize>,
+}
+
+struct EmbeddingRow {
+    // Position of the file of the row, in the sorted files of the folder
+    file: usize,
+    partner_id: i32,
+    product_id: i64,
+    embedding: Vec<f32>,
+    is_recommendable: bool,
+}
+
+impl Loader {
+    /// Reads the (partner_id, product_id, embedding, is_recommendable) rows of the `.parquet`
+    /// files of the folder, and builds one index per partner and recommendability, handled
+    /// as chunk 0 by `LoadOptions::policy`. Indices which need training are trained on all
+    /// their vectors.
+    pub fn load_parquet_folder<P: AsRef<Path>>(
+        path: P,
+        parquet_options: &ParquetOptions,
+        options: &LoadOptions,
+    ) -> Result<(HashMap<i32, KnnIndex>, LoadReport), KnnError> {
+        let path = path.as_ref();
+        let start = Instant::now();
+        info!("Starting to load parquet files of {}", path.display());
+        let metric = Distance::from_str(&parquet_options.metric)?;
+        if parquet_options.factory.contains("IDMap") {
+            return Err(KnnError::InvalidParquet(
+                path.display().to_string(),
+                format!(
+                    "factory {} can't contain an IDMap, the vectors are identified by their position",
+                    parquet_options.factory
+                ),
+            ));
+        }
+
+        let mut files: Vec<PathBuf> = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<_, _>>()?;
+        files.retain(|f| f.extension().is_some_and(|ext| ext == "parquet"));
+        files.sort();
+
+        let positions: Vec<usize> = (0..files.len()).collect();
+        let read = parallel_map(&positions, options.threads(), |i| {
+            Loader::read_parquet_file(&files[*i], *i)
+        });
+        let mut groups: BTreeMap<(i32, bool), Vec<EmbeddingRow>> = BTreeMap::new();
+        let mut dim = parquet_options.dimension;
+        for rows in read {
+            for row in rows? {
+                dim.get_or_insert(row.embedding.len());
+                groups
+                    .entry((row.partner_id, row.is_recommendable))
+                    .or_default()
+                    .push(row);
+            }
+        }
+        let dim = match dim {
+            Some(dim) if !groups.is_empty() => dim,
+            _ => return Ok((HashMap::new(), LoadReport::default())),
+        };
+        info!(
+            "Read {} embeddings of dimension {} from {} files",
+            groups.values().map(|g| g.len()).sum::<usize>(),
+            dim,
+            files.len()
+        );
+
+        let groups: Vec<((i32, bool), Vec<EmbeddingRow>)> = groups.into_iter().collect();
+        let built = parallel_map(&groups, options.threads(), |((partner_id, _), rows)| {
+            let index_start = Instant::now();
+            let index = Loader::build_index(&files, rows, dim, parquet_options, metric);
+            debug!(
+                "Built index of partner {} ({} embeddings) in {:?}",
+                partner_id,
+                rows.len(),
+                index_start.elapsed()
+            );
+            index
+        });
+        let (chunks, report) = Loader::apply_policy(
+            path,
+            options.policy,
+            groups
+                .iter()
+                .map(|((partner_id, is_recommendable), _)| (*partner_id, 0, *is_recommendable)),
+            built,
+        )?;
+
+        let mut indices: HashMap<i32, KnnIndex> = HashMap::new();
+        for ((partner_id, position), index) in chunks {
+            let ki = indices.entry(partner_id).or_default();
+            let (_, is_recommendable) = groups[position].0;
+            if is_recommendable {
+                ki.add_reco_index(index)
+            } else {
+                ki.add_non_reco_index(index)
+            }
+        }
+        info!(
+            "Load of parquet files of {} done in {:?}, {} chunks loaded, {} skipped",
+            path.display(),
+            start.elapsed(),
+            report.loaded_chunks,
+            report.skipped_chunks.len()
+        );
+        Ok((indices, report))
+    }
+
+    fn read_parquet_file(path: &Path, file: usize) -> Result<Vec<EmbeddingRow>, KnnError> {
+        let invalid = |reason: String| KnnError::InvalidParquet(path.display().to_string(), reason);
+        let reader = SerializedFileReader::new(std::fs::File::open(path)?)?;
+        let fields = reader
+            .metadata()
+            .file_metadata()
+            .schema_descr()
+            .root_schema()
+            .get_fields();
+        let column = |name: &str| {
+            fields
+                .iter()
+                .position(|f| f.name() == name)
+                .ok_or_else(|| invalid(format!("column {} not found", name)))
+        };
+        let partner_id = column("partner_id")?;
+        let product_id = column("product_id")?;
+        let embedding = column("embedding")?;
+        let is_recommendable = column("is_recommendable")?;
+
+        let mut rows = vec![];
+        for row in reader.get_row_iter(None)? {
+            let row = row?;
+            let values = row
+                .get_list(embedding)?
+                .elements()
+                .iter()
+                .map(|v| match v {
+                    Field::Float(v) => Ok(*v),
+                    Field::Double(v) => Ok(*v as f32),
+                    _ => Err(invalid(format!("embedding value {} is not a float", v))),
+                })
+                .collect::<Result<Vec<f32>, KnnError>>()?;
+            rows.push(EmbeddingRow {
+                file,
+                partner_id: row.get_int(partner_id)?,
+                product_id: row.get_long(product_id)?,
+                embedding: values,
+                is_recommendable: row.get_bool(is_recommendable)?,
+            });
+        }
+        Ok(rows)
+    }
+
+    fn build_index(
+        files: &[PathBuf],
+        rows: &[EmbeddingRow],
+        dim: usize,
+        parquet_options: &ParquetOptions,
+        metric: Distance,
+    ) -> Result<WrappedIndex, KnnError> {
+        if let Some(row) = rows.iter().find(|r| r.embedding.len() != dim) {
+            return Err(KnnError::InvalidParquet(
+                files[row.file].display().to_string(),
+                format!(
+                    "embedding of product {} of partner {} has dimension {} instead of {}",
+                    row.product_id,
+                    row.partner_id,
+                    row.embedding.len(),
+                    dim
+                ),
+            ));
+        }
+        // Each product must have a single vector for the mapping to cover the whole index
+        let mut products = HashSet::with_capacity(rows.len());
+        if let Some(row) = rows.iter().find(|r| !products.insert(r.product_id)) {
+            return Err(KnnError::InvalidParquet(
+                files[row.file].display().to_string(),
+                format!(
+                    "product {} of partner {} appears several times",
+                    row.product_id, row.partner_id
+                ),
+            ));
+        }
+        let mut data: Vec<f32> = rows
+            .iter()
+            .flat_map(|r| r.embedding.iter().copied())
+            .collect();
+        // The angular score expects normalized vectors
+        if metric == Distance::Angular {
+            for vector in data.chunks_mut(dim.max(1)) {
+                let norm = squared_l2_norm(vector).sqrt();
+                if norm > 0f32 {
+                    vector.iter_mut().for_each(|v| *v /= norm);
+                }
+            }
+        }
+        let metric_type = match metric {
+            Distance::InnerProduct => MetricType::InnerProduct,
+            Distance::Euclidean | Distance::Angular => MetricType::L2,
+        };
+        let mut index = faiss::index_factory(dim as u32, &parquet_options.factory, metric_type)?;
+        if !index.is_trained() {
+            index.train(&data)?;
+        }
+        index.add(&data)?;
+
+        let mapping = rows
+            .iter()
+            .enumerate()
+            .map(|(i, r)| (r.product_id, Idx::new(i as u64)))
+            .collect();
+        let norm = data
+            .chunks(dim.max(1))
+            .map(|vector| squared_l2_norm(vector).sqrt())
+            .collect();
+        let labels = rows.iter().map(|r| r.product_id).collect();
+        Ok(
+            WrappedIndex::new(Box::new(index), LabelMapping::Map(mapping), norm, metric)
+                .with_positional_labels(labels),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::data_type::{BoolType, DataType, DoubleType, FloatType, Int32Type, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+    use std::sync::Arc;
+    use tempdir::TempDir;
+
+    const ROWS: [(i32, i64, bool); 3] = [(1, 10, true), (1, 11, false), (2, 10, true)];
+
+    /// Writes `ROWS` with 2 dimensional `embeddings` of `element_type`, the columns
+    /// being in another order than the one of `EmbeddingRow`.
+    fn write_file<E: DataType>(path: &Path, element_type: &str, embeddings: &[E::T]) {
+        let schema = format!(
+            "message schema {{
+                REQUIRED BOOLEAN is_recommendable;
+                REQUIRED INT64 product_id;
+                REQUIRED GROUP embedding (LIST) {{
+                    REPEATED GROUP list {{
+                        REQUIRED {} element;
+                    }}
+                }}
+                REQUIRED INT32 partner_id;
+            }}",
+            element_type
+        );
+        let schema = Arc::new(parse_message_type(&schema).unwrap());
+        let properties = Arc::new(WriterProperties::builder().build());
+        let file = std::fs::File::create(path).unwrap();
+        let mut writer = SerializedFileWriter::new(file, schema, properties).unwrap();
+        let mut row_group = writer.next_row_group().unwrap();
+        let recommendables: Vec<bool> = ROWS.iter().map(|r| r.2).collect();
+        let products: Vec<i64> = ROWS.iter().map(|r| r.1).collect();
+        let partners: Vec<i32> = ROWS.iter().map(|r| r.0).collect();
+        let definition_levels = vec![1i16; embeddings.len()];
+        let repetition_levels: Vec<i16> = (0..embeddings.len()).map(|i| (i % 2) as i16).collect();
+        let mut column = 0;
+        while let Some(mut column_writer) = row_group.next_column().unwrap() {
+            match column {
+                0 => column_writer
+                    .typed::<BoolType>()
+                    .write_batch(&recommendables, None, None),
+                1 => column_writer
+                    .typed::<Int64Type>()
+                    .write_batch(&products, None, None),
+                2 => column_writer.typed::<E>().write_batch(
+                    embeddings,
+                    Some(&definition_levels[..]),
+                    Some(&repetition_levels[..]),
+                ),
+                _ => column_writer
+                    .typed::<Int32Type>()
+                    .write_batch(&partners, None, None),
+            }
+            .unwrap();
+            column_writer.close().unwrap();
+            column += 1;
+        }
+        row_group.close().unwrap();
+        writer.close().unwrap();
+    }
+
+    fn check_rows(rows: &[EmbeddingRow]) {
+        let read: Vec<(i32, i64, bool)> = rows
+            .iter()
+            .map(|r| (r.partner_id, r.product_id, r.is_recommendable))
+            .collect();
+        assert_eq!(read, ROWS.to_vec());
+        let embeddings: Vec<Vec<f32>> = rows.iter().map(|r| r.embedding.clone()).collect();
+        assert_eq!(
+            embeddings,
+            vec![vec![0.5, 1.0], vec![-2.0, 0.25], vec![3.0, 0.0]]
+        );
+        assert!(rows.iter().all(|r| r.file == 4));
+    }
+
+    #[test]
+    fn read_parquet_file_reads_float_embeddings() {
+        let dir = TempDir::new("parquetloader").unwrap();
+        let path = dir.path().join("embeddings.parquet");
+        write_file::<FloatType>(&path, "FLOAT", &[0.5, 1.0, -2.0, 0.25, 3.0, 0.0]);
+        check_rows(&Loader::read_parquet_file(&path, 4).unwrap());
+    }
+
+    #[test]
+    fn read_parquet_file_converts_double_embeddings() {
+        let dir = TempDir::new("parquetloader").unwrap();
+        let path = dir.path().join("embeddings.parquet");
+        write_file::<DoubleType>(&path, "DOUBLE", &[0.5, 1.0, -2.0, 0.25, 3.0, 0.0]);
+        check_rows(&Loader::read_parquet_file(&path, 4).unwrap());
+    }
+
+    #[test]
+    fn read_parquet_file_rejects_other_embedding_types() {
+        let dir = TempDir::new("parquetloader").unwrap();
+        let path = dir.path().join("embeddings.parquet");
+        write_file::<Int32Type>(&path, "INT32", &[1, 2, 3, 4, 5, 6]);
+        assert!(matches!(
+            Loader::read_parquet_file(&path, 0),
+            Err(KnnError::InvalidParquet(_, reason)) if reason.contains("is not a float")
+        ));
+    }
+
+    #[test]
+    fn read_parquet_file_requires_every_column() {
+        let dir = TempDir::new("parquetloader").unwrap();
+        let path = dir.path().join("embeddings.parquet");
+        let schema = "message schema { REQUIRED INT32 partner_id; }";
+        let schema = Arc::new(parse_message_type(schema).unwrap());
+        let properties = Arc::new(WriterProperties::builder().build());
+        let file = std::fs::File::create(&path).unwrap();
+        SerializedFileWriter::new(file, schema, properties)
+            .unwrap()
+            .close()
+            .unwrap();
+        assert!(matches!(
+            Loader::read_parquet_file(&path, 0),
+            Err(KnnError::InvalidParquet(_, reason)) if reason == "column product_id not found"
+        ));
+    }
+
+    #[test]
+    fn build_index_rejects_repeated_products() {
+        let files = vec![PathBuf::from("a.parquet"), PathBuf::from("b.parquet")];
+        let row = |file, product_id| EmbeddingRow {
+            file,
+            partner_id: 1,
+            product_id,
+            embedding: vec![0f32, 1f32],
+            is_recommendable: true,
+        };
+        let rows = vec![row(0, 10), row(0, 11), row(1, 10)];
+        let options = ParquetOptions {
+            factory: "Flat".to_string(),
+            metric: "euclidean".to_string(),
+            dimension: None,
+        };
+        match Loader::build_index(&files, &rows, 2, &options, Distance::Euclidean) {
+            Err(KnnError::InvalidParquet(file, reason)) => {
+                assert_eq!(file, "b.parquet");
+                assert_eq!(reason, "product 10 of partner 1 appears several times");
+            }
+            _ => panic!("repeated product 10 should be rejected"),
+        }
+    }
+}
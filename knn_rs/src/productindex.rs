@@ -5,13 +5,39 @@ pub trait ProductIndex {
     fn dimension(&self) -> usize;
     fn list_labels(&self) -> Result<Vec<i64>, KnnError>;
     fn get_item(&self, id: i64) -> Result<Option<Vec<f32>>, KnnError>;
+    fn get_norm(&self, id: i64) -> Result<Option<f32>, KnnError>;
     fn search(&self, embedding: &[f32], output: usize) -> Result<Vec<IndexResult>, KnnError>;
+
+    /// Searches `nq` embeddings stored one after the other,
+    /// returning one result list per embedding.
+    fn search_batch(
+        &self,
+        embeddings: &[f32],
+        nq: usize,
+        output: usize,
+    ) -> Result<Vec<Vec<IndexResult>>, KnnError> {
+        if nq == 0 {
+            return Ok(vec![]);
+        }
+        embeddings
+            .chunks((embeddings.len() / nq).max(1))
+            .map(|embedding| self.search(embedding, output))
+            .collect()
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct IndexResult {
     pub label: i64,
     pub distance: f32,
+    /// Score normalized according to the index metric, higher is better.
+    pub score: f32,
+    pub dotproduct: f32,
+    pub squared_l2_norm: f32,
+}
+
+pub fn squared_l2_norm(embedding: &[f32]) -> f32 {
+    embedding.iter().map(|v| v * v).sum()
 }
 
 impl PartialOrd for IndexResult {
@@ -22,8 +48,51 @@ impl PartialOrd for IndexResult {
 
 impl Eq for IndexResult {}
 
+/// Results are ordered from the best to the worst score, so sorting a list of results
+/// puts the closest items first.
 impl Ord for IndexResult {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.distance.total_cmp(&other.distance)
+        other.score.total_cmp(&self.score)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(label: i64, score: f32) -> IndexResult {
+        IndexResult {
+            label,
+            distance: 0f32,
+            score,
+            dotproduct: 0f32,
+            squared_l2_norm: 0f32,
+        }
+    }
+
+    #[test]
+    fn results_are_sorted_from_the_best_score() {
+        let mut results = vec![
+            result(1, 0.2),
+            result(2, 0.9),
+            result(3, -1.0),
+            result(4, 0.5),
+        ];
+        results.sort();
+        let labels: Vec<i64> = results.iter().map(|r| r.label).collect();
+        assert_eq!(labels, vec![2, 4, 1, 3]);
+    }
+
+    #[test]
+    fn nan_scores_are_sorted_first() {
+        // total_cmp orders positive NaNs above every other score
+        let mut results = vec![
+            result(1, 0.5),
+            result(2, f32::NAN),
+            result(3, f32::INFINITY),
+        ];
+        results.sort();
+        let labels: Vec<i64> = results.iter().map(|r| r.label).collect();
+        assert_eq!(labels, vec![2, 3, 1]);
     }
 }
@@ -1,71 +1,172 @@
-use parking_lot::RwLock;
-use std::{collections::HashMap, sync::Arc};
+use faiss::index::NativeIndex;
+use faiss::{Idx, Index};
 
 use crate::{
-    productindex::{IndexResult, ProductIndex},
-    KnnError,
+    labelmapping::LabelMapping,
+    productindex::{squared_l2_norm, IndexResult, ProductIndex},
+    Distance, KnnError,
 };
 
 pub struct WrappedIndex {
     // Product to faiss id mapping
-    mapping: HashMap<i64, faiss::Idx>,
+    mapping: LabelMapping,
+    // Product ids by faiss id, when the index has positional ids instead of product ids
+    labels: Option<Vec<i64>>,
     norm: Vec<f32>,
-    index: Arc<RwLock<Box<dyn faiss::Index + Sync + Send>>>,
+    metric: Distance,
+    index: Box<dyn NativeIndex + Sync + Send>,
 }
 
 impl WrappedIndex {
     pub fn new(
-        index: Box<dyn faiss::Index + Sync + Send>,
-        mapping: HashMap<i64, faiss::Idx>,
+        index: Box<dyn NativeIndex + Sync + Send>,
+        mapping: LabelMapping,
         norm: Vec<f32>,
+        metric: Distance,
     ) -> WrappedIndex {
         WrappedIndex {
-            index: Arc::new(RwLock::new(index)),
+            index,
             mapping,
+            labels: None,
             norm,
+            metric,
         }
     }
+
+    /// For indices built without `IDMap`, whose search returns the position of the vectors:
+    /// `labels` holds the product id of each position.
+    pub fn with_positional_labels(mut self, labels: Vec<i64>) -> WrappedIndex {
+        self.labels = Some(labels);
+        self
+    }
+
+    pub fn metric(&self) -> Distance {
+        self.metric
+    }
+
+    fn to_results(
+        &self,
+        embedding: &[f32],
+        labels: &[Idx],
+        distances: &[f32],
+    ) -> Result<Vec<IndexResult>, KnnError> {
+        let metric_type = self.index.metric_type();
+        let is_inner_product = metric_type == faiss::MetricType::InnerProduct;
+        let query_norm = squared_l2_norm(embedding);
+        let mut res = labels
+            .iter()
+            .zip(distances)
+            // faiss pads the result with missing labels when less than k items are found
+            .filter(|(label, _)| label.is_some())
+            .map(|(label, distance)| (label.to_native(), *distance))
+            .map(|(label, distance)| match &self.labels {
+                Some(labels) => (labels[label as usize], distance),
+                None => (label, distance),
+            })
+            .map(|(label, distance)| {
+                let norm = self.get_norm(label)?.unwrap_or(0f32);
+                let squared_l2_norm = norm * norm;
+                // For L2 indices, distance = |q|^2 + |x|^2 - 2 <q, x>
+                let dotproduct = if is_inner_product {
+                    distance
+                } else {
+                    (query_norm + squared_l2_norm - distance) / 2f32
+                };
+                Ok(IndexResult {
+                    label,
+                    distance,
+                    score: self.metric.score(distance, metric_type),
+                    dotproduct,
+                    squared_l2_norm,
+                })
+            })
+            .collect::<Result<Vec<IndexResult>, KnnError>>()?;
+        res.sort();
+        Ok(res)
+    }
+
+    /// Searches the `k` nearest neighbors of the `nq` queries stored one after the other
+    /// in `queries`. Faiss search doesn't modify CPU indices, so it is called through a
+    /// shared reference and several threads can search the same index concurrently.
+    fn search_raw(
+        &self,
+        queries: &[f32],
+        nq: usize,
+        k: usize,
+    ) -> Result<(Vec<f32>, Vec<Idx>), KnnError> {
+        let d = self.dimension();
+        if queries.len() != nq * d {
+            return Err(KnnError::InvalidDimension(d, queries.len() / nq.max(1)));
+        }
+        let mut distances = vec![0f32; nq * k];
+        let mut labels = vec![Idx::none(); nq * k];
+        let code = unsafe {
+            faiss_sys::faiss_Index_search(
+                self.index.inner_ptr(),
+                nq as faiss_sys::idx_t,
+                queries.as_ptr(),
+                k as faiss_sys::idx_t,
+                distances.as_mut_ptr(),
+                labels.as_mut_ptr() as *mut faiss_sys::idx_t,
+            )
+        };
+        if code != 0 {
+            let error = faiss::error::NativeError::from_last_error(code);
+            return Err(KnnError::FaissError(error.into()));
+        }
+        Ok((distances, labels))
+    }
 }
 
 impl ProductIndex for WrappedIndex {
     fn count(&self) -> usize {
-        let rguard = self.index.read();
-        rguard.ntotal() as usize
+        self.index.ntotal() as usize
     }
 
     fn dimension(&self) -> usize {
-        let rguard = self.index.read();
-        rguard.d() as usize
+        self.index.d() as usize
     }
 
     fn get_item(&self, id: i64) -> Result<Option<Vec<f32>>, KnnError> {
-        let inner_id = self.mapping.get(&id);
+        let inner_id = self.mapping.get(id);
         match inner_id {
-            Some(id) => {
-                let rguard = self.index.read();
-                Ok(Some(rguard.reconstruct(*id)?))
-            }
+            Some(id) => Ok(Some(self.index.reconstruct(id)?)),
             None => Ok(None),
         }
     }
 
+    fn get_norm(&self, id: i64) -> Result<Option<f32>, KnnError> {
+        Ok(self
+            .mapping
+            .get(id)
+            .and_then(|inner_id| inner_id.get())
+            .and_then(|inner_id| self.norm.get(inner_id as usize))
+            .copied())
+    }
+
     fn search(&self, embedding: &[f32], k: usize) -> Result<Vec<IndexResult>, KnnError> {
-        let mut wguard = self.index.write();
-        let r = wguard.search(embedding, k)?;
-        let res = r
-            .labels
-            .into_iter()
-            .map(|d| d.to_native())
-            .zip(r.distances)
-            .map(|a| IndexResult {
-                label: a.0,
-                distance: a.1,
-            })
-            .collect();
-        Ok(res)
+        let (distances, labels) = self.search_raw(embedding, 1, k)?;
+        self.to_results(embedding, &labels, &distances)
+    }
+
+    fn search_batch(
+        &self,
+        embeddings: &[f32],
+        nq: usize,
+        k: usize,
+    ) -> Result<Vec<Vec<IndexResult>>, KnnError> {
+        if nq == 0 || k == 0 {
+            return Ok((0..nq).map(|_| vec![]).collect());
+        }
+        let (distances, labels) = self.search_raw(embeddings, nq, k)?;
+        embeddings
+            .chunks(self.dimension().max(1))
+            .zip(labels.chunks(k).zip(distances.chunks(k)))
+            .map(|(embedding, (labels, distances))| self.to_results(embedding, labels, distances))
+            .collect()
     }
 
     fn list_labels(&self) -> Result<Vec<i64>, KnnError> {
-        Ok(self.mapping.keys().copied().collect())
+        Ok(self.mapping.labels())
     }
 }
@@ -0,0 +1 @@
+tonic::include_proto!("com.criteo.recommendation.knn.admin");
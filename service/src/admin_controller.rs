@@ -0,0 +1,180 @@
+use crate::admin::{knn_admin_server::*, *};
+use crate::knn_controller::record_load_reports;
+use arc_swap::ArcSwap;
+use knn_rs::knn_tf::SessionConfig;
+use knn_rs::knncountry::KnnByCountry;
+use knn_rs::knnservice::{Model, ModelType};
+use knn_rs::KnnError;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tonic::{Request, Response, Status};
+
+/// Updates are applied on a copy of the served indices which is swapped once ready,
+/// so searches are never blocked by a load.
+pub struct AdminController {
+    knn_country: Arc<ArcSwap<KnnByCountry>>,
+    update_lock: Arc<Mutex<()>>,
+}
+
+impl AdminController {
+    pub fn new(
+        knn_country: Arc<ArcSwap<KnnByCountry>>,
+        update_lock: Arc<Mutex<()>>,
+    ) -> AdminController {
+        AdminController {
+            knn_country,
+            update_lock,
+        }
+    }
+
+    async fn update<F>(&self, f: F) -> Result<(), Status>
+    where
+        F: FnOnce(&mut KnnByCountry) -> Result<(), KnnError> + Send + 'static,
+    {
+        let _guard = self.update_lock.lock().await;
+        let mut knn_country = KnnByCountry::clone(&self.knn_country.load());
+        let knn_country =
+            tokio::task::spawn_blocking(move || f(&mut knn_country).map(|_| knn_country))
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .map_err(|e| match e {
+                    KnnError::ModelNotFound(_) => Status::not_found(e.to_string()),
+                    _ => Status::internal(e.to_string()),
+                })?;
+        record_load_reports(&knn_country);
+        self.knn_country.store(Arc::new(knn_country));
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl KnnAdmin for AdminController {
+    async fn load_country(
+        &self,
+        request: Request<LoadCountryRequest>,
+    ) -> Result<Response<()>, Status> {
+        let country = request.into_inner().country;
+        info!("Admin: loading country {}", country);
+        self.update(move |kc| kc.load_country(&country)).await?;
+        Ok(Response::new(()))
+    }
+
+    async fn unload_country(
+        &self,
+        request: Request<UnloadCountryRequest>,
+    ) -> Result<Response<()>, Status> {
+        let country = request.into_inner().country;
+        info!("Admin: unloading country {}", country);
+        if !self.knn_country.load().get_countries().contains(&country) {
+            return Err(Status::not_found(format!(
+                "country {} not available",
+                country
+            )));
+        }
+        self.update(move |kc| {
+            kc.unload_country(&country);
+            Ok(())
+        })
+        .await?;
+        Ok(Response::new(()))
+    }
+
+    async fn load_model(&self, request: Request<LoadModelRequest>) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        info!("Admin: loading model {}", request.name);
+        let model_type = ModelType::from_str(&request.model_type)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if !request.path.is_empty() && request.version.is_empty() {
+            return Err(Status::invalid_argument(
+                "a version is required for models with a path",
+            ));
+        }
+        let model = Model {
+            name: request.name,
+            model_path: (!request.path.is_empty()).then(|| PathBuf::from(request.path)),
+            model_type,
+            is_default: request.is_default,
+            version: (!request.version.is_empty()).then_some(request.version),
+            signature: (!request.signature.is_empty()).then_some(request.signature),
+            tensor_names: request.tensor_names,
+            session: request
+                .session
+                .map(|s| SessionConfig {
+                    intra_op_threads: (s.intra_op_threads > 0).then_some(s.intra_op_threads),
+                    inter_op_threads: (s.inter_op_threads > 0).then_some(s.inter_op_threads),
+                    executor_type: (!s.executor_type.is_empty()).then_some(s.executor_type),
+                    pool_size: (s.pool_size > 0).then_some(s.pool_size as usize),
+                })
+                .unwrap_or_default(),
+        };
+        self.update(move |kc| kc.load_model(model)).await?;
+        Ok(Response::new(()))
+    }
+
+    async fn set_default_model(
+        &self,
+        request: Request<SetDefaultModelRequest>,
+    ) -> Result<Response<()>, Status> {
+        let name = request.into_inner().name;
+        info!("Admin: setting default model to {}", name);
+        self.update(move |kc| kc.set_default_model(&name)).await?;
+        Ok(Response::new(()))
+    }
+
+    async fn list_loaded(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<ListLoadedResponse>, Status> {
+        let knn_country = self.knn_country.load();
+        let mut countries: Vec<LoadedCountry> = knn_country
+            .get_countries()
+            .into_iter()
+            .filter_map(|name| {
+                knn_country.get_service(&name).map(|s| LoadedCountry {
+                    models: s.list_models(),
+                    default_model: s.default_model().unwrap_or_default().to_string(),
+                    name,
+                })
+            })
+            .collect();
+        countries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Response::new(ListLoadedResponse {
+            version: knn_country.config().version.clone(),
+            countries,
+        }))
+    }
+    async fn get_load_reports(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<LoadReportsResponse>, Status> {
+        let knn_country = self.knn_country.load();
+        let mut reports: Vec<CountryLoadReport> = knn_country
+            .get_countries()
+            .into_iter()
+            .filter_map(|country| {
+                knn_country.get_service(&country).map(|s| {
+                    let report = s.load_report();
+                    CountryLoadReport {
+                        loaded_chunks: report.loaded_chunks as u64,
+                        skipped_chunks: report
+                            .skipped_chunks
+                            .iter()
+                            .map(|c| SkippedChunk {
+                                partner_id: c.partner_id,
+                                chunk_id: c.chunk_id,
+                                is_recommendable: c.is_recommendable,
+                                reason: c.reason.clone(),
+                            })
+                            .collect(),
+                        skipped_partners: report.skipped_partners.clone(),
+                        country,
+                    }
+                })
+            })
+            .collect();
+        reports.sort_by(|a, b| a.country.cmp(&b.country));
+        Ok(Response::new(LoadReportsResponse { reports }))
+    }
+}
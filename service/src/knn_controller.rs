@@ -1,8 +1,12 @@
 use crate::knn::{knn_server::*, *};
 use anyhow::Result;
+use arc_swap::ArcSwap;
+use knn_rs::embedding_computer::{ComputeOptions, UserEvent};
 use knn_rs::knncountry::{Config, KnnByCountry};
-use knn_rs::{embedding_computer::UserEvent, productindex::IndexResult};
-use metrics::{counter, histogram, Counter, Histogram};
+use knn_rs::knnservice::{KnnResult, KnnService};
+use knn_rs::KnnError;
+use metrics::{counter, gauge, histogram, Counter, Histogram};
+use std::sync::Arc;
 use tokio::time::Instant;
 use tonic::{Request, Response, Status};
 
@@ -26,7 +30,7 @@ impl<'a> Drop for TimeHandle<'a> {
 }
 
 pub struct KnnController {
-    knn_country: KnnByCountry,
+    knn_country: Arc<ArcSwap<KnnByCountry>>,
     metrics: ControllerMetrics,
 }
 impl KnnController {
@@ -37,32 +41,171 @@ impl KnnController {
             request_latency: histogram!("request_latency", &labels),
         };
         KnnController {
-            knn_country: KnnByCountry::new(config),
+            knn_country: Arc::new(ArcSwap::from_pointee(KnnByCountry::new(config))),
             metrics,
         }
     }
 
     pub fn load(&mut self) -> anyhow::Result<()> {
-        self.knn_country.load()?;
+        let mut knn_country = KnnByCountry::new(self.knn_country.load().config().clone());
+        knn_country.load()?;
+        record_load_reports(&knn_country);
+        self.knn_country.store(Arc::new(knn_country));
         Ok(())
     }
 
-    fn build_response(products: Vec<IndexResult>) -> KnnResponse {
-        let products = products
+    /// Shared handle on the loaded indices, swapped when a new version is loaded.
+    pub fn knn_country(&self) -> Arc<ArcSwap<KnnByCountry>> {
+        self.knn_country.clone()
+    }
+
+    fn user_events(request: &KnnRequest) -> Vec<UserEvent> {
+        request
+            .user_events
+            .iter()
+            .map(|event| UserEvent {
+                index: event.partner_id,
+                label: event.product_id,
+                timestamp: event.timestamp as u64,
+                event_type: event.event_type,
+            })
+            .collect()
+    }
+
+    fn compute_options(request: &KnnRequest) -> ComputeOptions {
+        ComputeOptions {
+            number_last_events: (request.number_last_events > 0)
+                .then_some(request.number_last_events as usize),
+            number_last_days: (request.number_last_days > 0)
+                .then_some(request.number_last_days as u32),
+            half_life: (request.half_life > 0.0).then_some(request.half_life),
+            allow_zero_user_embedding: request.allow_zero_user_embedding,
+            reference_timestamp: (request.reference_timestamp > 0)
+                .then_some(request.reference_timestamp),
+        }
+    }
+
+    fn model_name(request: &KnnRequest) -> Option<String> {
+        (!request.model_name.is_empty()).then(|| request.model_name.clone())
+    }
+
+    fn error_status(error: KnnError) -> Status {
+        match error {
+            KnnError::ModelNotFound(_) => Status::not_found(error.to_string()),
+            _ => Status::internal(error.to_string()),
+        }
+    }
+
+    /// Key of the request in experiments. Without routing key, the key is derived from the
+    /// publisher and the oldest event of the timeline, which stay the same as the user gets new
+    /// events, until the oldest one leaves the timeline sent by the client. Requests with
+    /// neither get the default model.
+    fn routing_key(request: &KnnRequest) -> Option<Vec<u8>> {
+        if !request.routing_key.is_empty() {
+            return Some(request.routing_key.as_bytes().to_vec());
+        }
+        let oldest = request
+            .user_events
+            .iter()
+            .min_by_key(|e| (e.timestamp, e.partner_id, e.product_id));
+        if oldest.is_none() && request.publisher_id.is_none() {
+            return None;
+        }
+        let mut key = vec![];
+        if let Some(publisher_id) = &request.publisher_id {
+            key.extend(publisher_id.id.to_be_bytes());
+        }
+        if let Some(oldest) = oldest {
+            key.extend(oldest.partner_id.to_be_bytes());
+            key.extend(oldest.product_id.to_be_bytes());
+            key.extend(oldest.timestamp.to_be_bytes());
+        }
+        Some(key)
+    }
+
+    /// Counts and times the request, selects its model and runs `search` with it on the
+    /// service of the request country.
+    fn search_with<F>(
+        &self,
+        request: &KnnRequest,
+        search: F,
+    ) -> Result<Response<KnnResponse>, Status>
+    where
+        F: FnOnce(
+            &KnnService,
+            &[UserEvent],
+            Option<String>,
+            &ComputeOptions,
+        ) -> Result<KnnResult, KnnError>,
+    {
+        self.metrics.request_count.increment(1);
+        let _timer = TimeHandle::new(&self.metrics.request_latency);
+        let knn_country = self.knn_country.load();
+        let knn_service = knn_country.get_service(&request.country).ok_or_else(|| {
+            Status::not_found(format!("country {} not available", request.country))
+        })?;
+        let events = KnnController::user_events(request);
+        let (model, experiment) = knn_service.select_model(
+            KnnController::model_name(request),
+            KnnController::routing_key(request).as_deref(),
+        );
+        let result = search(
+            knn_service,
+            &events,
+            model.clone(),
+            &KnnController::compute_options(request),
+        )
+        .map_err(KnnController::error_status)?;
+        if let (Some(experiment), Some(model)) = (experiment, &model) {
+            counter!(
+                "experiment_request_count",
+                "experiment" => experiment.to_string(),
+                "model" => model.clone()
+            )
+            .increment(1);
+        }
+        Ok(Response::new(KnnController::build_response(
+            result, model, experiment,
+        )))
+    }
+
+    fn build_response(
+        result: KnnResult,
+        model: Option<String>,
+        experiment: Option<&str>,
+    ) -> KnnResponse {
+        let products = result
+            .products
             .iter()
             .map(|ir| Product {
                 product_id: ir.label,
-                score: ir.distance,
-                dotproduct: 0f32,
-                squared_l2_norm: 0f32,
+                score: ir.score,
+                dotproduct: ir.dotproduct,
+                squared_l2_norm: ir.squared_l2_norm,
             })
             .collect();
         KnnResponse {
             products,
-            ..Default::default()
+            user_events_used_count: result.user_events_used_count as i32,
+            squared_l2_query_norm: result.squared_l2_query_norm,
+            model_name: model.unwrap_or_default(),
+            experiment: experiment.unwrap_or_default().to_string(),
         }
     }
 }
+/// Publishes the number of chunks and partners skipped by the last load of each country.
+pub fn record_load_reports(knn_country: &KnnByCountry) {
+    for country in knn_country.get_countries() {
+        if let Some(knn_service) = knn_country.get_service(&country) {
+            let report = knn_service.load_report();
+            gauge!("load_skipped_chunk_count", "country" => country.clone())
+                .set(report.skipped_chunks.len() as f64);
+            gauge!("load_skipped_partner_count", "country" => country)
+                .set(report.skipped_partners.len() as f64);
+        }
+    }
+}
+
 struct ControllerMetrics {
     request_count: Counter,
     request_latency: Histogram,
@@ -71,47 +214,36 @@ struct ControllerMetrics {
 #[tonic::async_trait]
 impl Knn for KnnController {
     async fn search(&self, request: Request<KnnRequest>) -> Result<Response<KnnResponse>, Status> {
-        self.metrics.request_count.increment(1);
-        TimeHandle::new(&self.metrics.request_latency);
         let request: KnnRequest = request.into_inner();
         debug!("Received request with country: {}", request.country);
-        if let Some(knn_service) = self.knn_country.get_service(&request.country) {
-            let events: Vec<UserEvent> = request
-                .user_events
-                .iter()
-                .map(|event| UserEvent {
-                    index: event.partner_id,
-                    label: event.product_id,
-                    timestamp: event.timestamp as u64,
-                    event_type: event.event_type,
-                })
-                .collect();
-            let result = knn_service.get_closest_items(
-                &events,
+        self.search_with(&request, |knn_service, events, model, options| {
+            knn_service.get_closest_items(
+                events,
                 request.index_id,
                 request.result_count as usize,
-                None,
-            );
-
-            match result {
-                Ok(r) => {
-                    let response = Response::new(KnnController::build_response(r));
-                    Ok(response)
-                }
-                Err(error) => Err(Status::internal(error.to_string())),
-            }
-        } else {
-            Err(Status::not_found(format!(
-                "country {} not available",
-                request.country
-            )))
-        }
+                model,
+                options,
+            )
+        })
     }
     async fn multi_search(
         &self,
-        _request: Request<KnnRequest>,
+        request: Request<KnnRequest>,
     ) -> Result<Response<KnnResponse>, Status> {
-        Err(Status::unimplemented(""))
+        let request: KnnRequest = request.into_inner();
+        debug!(
+            "Received multi search request with country: {}",
+            request.country
+        );
+        self.search_with(&request, |knn_service, events, model, options| {
+            knn_service.get_closest_items_multi(
+                events,
+                &request.index_ids,
+                request.result_count as usize,
+                model,
+                options,
+            )
+        })
     }
 
     async fn get_available_countries(
@@ -120,6 +252,7 @@ impl Knn for KnnController {
     ) -> Result<Response<AvailableCountriesResponse>, Status> {
         let countries: Vec<CountryInfo> = self
             .knn_country
+            .load()
             .get_countries()
             .into_iter()
             .map(|c| CountryInfo {
@@ -131,14 +264,100 @@ impl Knn for KnnController {
     }
     async fn get_indices_for_country(
         &self,
-        _request: Request<IndicesRequest>,
+        request: Request<IndicesRequest>,
     ) -> Result<Response<IndicesResponse>, Status> {
-        Err(Status::unimplemented(""))
+        let request: IndicesRequest = request.into_inner();
+        let knn_country = self.knn_country.load();
+        if let Some(knn_service) = knn_country.get_service(&request.country) {
+            match knn_service.list_indices() {
+                Ok(stats) => {
+                    let indices = stats
+                        .into_iter()
+                        .map(|s| IndexInfo {
+                            partner_id: s.index_id,
+                            embeddings_count: s.reco_count as i64,
+                            non_reco_embeddings_count: s.non_reco_count as i64,
+                        })
+                        .collect();
+                    Ok(Response::new(IndicesResponse { indices }))
+                }
+                Err(error) => Err(Status::internal(error.to_string())),
+            }
+        } else {
+            Err(Status::not_found(format!(
+                "country {} not available",
+                request.country
+            )))
+        }
+    }
+    async fn get_models_for_country(
+        &self,
+        request: Request<ModelsRequest>,
+    ) -> Result<Response<ModelsResponse>, Status> {
+        let request: ModelsRequest = request.into_inner();
+        let knn_country = self.knn_country.load();
+        if let Some(knn_service) = knn_country.get_service(&request.country) {
+            let models = knn_service
+                .models()
+                .into_iter()
+                .map(|m| ModelInfo {
+                    name: m.name.clone(),
+                    model_type: m.model_type.to_string(),
+                    version: m.version.clone().unwrap_or_default(),
+                    is_default: knn_service.default_model() == Some(m.name.as_str()),
+                })
+                .collect();
+            Ok(Response::new(ModelsResponse { models }))
+        } else {
+            Err(Status::not_found(format!(
+                "country {} not available",
+                request.country
+            )))
+        }
     }
     async fn get_indexed_products(
         &self,
-        _request: Request<IndexedProductsRequest>,
+        request: Request<IndexedProductsRequest>,
     ) -> Result<Response<IndexedProductsResponse>, Status> {
-        Err(Status::unimplemented(""))
+        let request: IndexedProductsRequest = request.into_inner();
+        let max_count = if request.max_count > 0 {
+            request.max_count as usize
+        } else {
+            usize::MAX
+        };
+        let knn_country = self.knn_country.load();
+        if let Some(knn_service) = knn_country.get_service(&request.country) {
+            let result = if request.random_sample {
+                knn_service
+                    .sample_labels(request.index_id, max_count, request.seed)
+                    .map(|labels| (labels, None))
+            } else {
+                let after = if request.page_token.is_empty() {
+                    None
+                } else {
+                    let cursor = request.page_token.parse::<i64>().map_err(|_| {
+                        Status::invalid_argument(format!(
+                            "invalid page token {}",
+                            request.page_token
+                        ))
+                    })?;
+                    Some(cursor)
+                };
+                knn_service.list_labels_page(request.index_id, after, max_count)
+            };
+
+            match result {
+                Ok((product_id, next)) => Ok(Response::new(IndexedProductsResponse {
+                    product_id,
+                    next_page_token: next.map(|n| n.to_string()).unwrap_or_default(),
+                })),
+                Err(error) => Err(Status::internal(error.to_string())),
+            }
+        } else {
+            Err(Status::not_found(format!(
+                "country {} not available",
+                request.country
+            )))
+        }
     }
 }
@@ -0,0 +1,93 @@
+use crate::knn_controller::record_load_reports;
+use arc_swap::ArcSwap;
+use knn_rs::knncountry::KnnByCountry;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+/// Watches the indices root folder and loads new embedding versions in the background.
+/// The new indices are loaded next to the current ones and swapped once ready, requests
+/// still running on the previous version keep it alive until they are done.
+pub struct IndexReloader {
+    knn_country: Arc<ArcSwap<KnnByCountry>>,
+    update_lock: Arc<Mutex<()>>,
+    interval: Duration,
+    // Versions which failed to load, not retried until the process restarts
+    failed_versions: std::sync::Mutex<HashSet<String>>,
+}
+
+impl IndexReloader {
+    pub fn new(
+        knn_country: Arc<ArcSwap<KnnByCountry>>,
+        update_lock: Arc<Mutex<()>>,
+        interval: Duration,
+    ) -> IndexReloader {
+        IndexReloader {
+            knn_country,
+            update_lock,
+            interval,
+            failed_versions: Default::default(),
+        }
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        info!(
+            "Watching for new index versions every {}s",
+            self.interval.as_secs()
+        );
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(self.interval);
+            // The first tick completes immediately
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.reload_if_needed().await {
+                    error!("Failed to reload indices: {}", e);
+                }
+            }
+        })
+    }
+
+    async fn reload_if_needed(&self) -> anyhow::Result<()> {
+        let _guard = self.update_lock.lock().await;
+        let config = self.knn_country.load().config().clone();
+        let latest_config = config.clone();
+        let failed_versions = self.failed_versions.lock().unwrap().clone();
+        let latest_version =
+            tokio::task::spawn_blocking(move || latest_config.latest_version(&failed_versions))
+                .await??;
+
+        let version = match latest_version {
+            Some(version) if version > config.version => version,
+            _ => return Ok(()),
+        };
+
+        info!(
+            "Loading new index version {} (current {})",
+            version, config.version
+        );
+        let mut new_config = config;
+        new_config.version = version.clone();
+        let loaded = tokio::task::spawn_blocking(move || {
+            let mut knn_country = KnnByCountry::new(new_config);
+            knn_country.load().map(|_| knn_country)
+        })
+        .await?;
+        let knn_country = match loaded {
+            Ok(knn_country) => knn_country,
+            Err(e) => {
+                warn!("Index version {} won't be retried", version);
+                self.failed_versions.lock().unwrap().insert(version);
+                return Err(e.into());
+            }
+        };
+
+        // The previous version is dropped once the last in-flight request releases it
+        record_load_reports(&knn_country);
+        self.knn_country.store(Arc::new(knn_country));
+        info!("Now serving index version {}", version);
+        Ok(())
+    }
+}
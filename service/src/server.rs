@@ -1,12 +1,18 @@
 #[macro_use]
 extern crate tracing;
 
+mod admin;
+mod admin_controller;
 mod knn;
 mod knn_controller;
+mod reloader;
 mod settings;
 
+use crate::admin::knn_admin_server::KnnAdminServer;
+use crate::admin_controller::AdminController;
 use crate::knn::knn_server::*;
 use crate::knn_controller::KnnController;
+use crate::reloader::IndexReloader;
 use anyhow::anyhow;
 use clap::Parser;
 use knn_rs::knnservice::{Model, ModelType};
@@ -16,7 +22,10 @@ use std::{
     net::SocketAddr,
     path::{Path, PathBuf},
     str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
+use tokio::sync::Mutex;
 use tonic::transport::Server;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
@@ -59,7 +68,9 @@ async fn run(config: KnnConfig, args: KnnServiceArgs) -> Result<(), Box<dyn std:
         .set_serving::<KnnServer<KnnController>>()
         .await;
 
+    let admin_port = config.server.admin_port;
     let indices_root_path = expand(&config.index_config.indices_root)?;
+    let reload_interval_sec = config.index_config.reload_interval_sec;
     let models = config
         .model_config
         .models
@@ -75,6 +86,9 @@ async fn run(config: KnnConfig, args: KnnServiceArgs) -> Result<(), Box<dyn std:
                 model_type: ModelType::from_str(&m.model_type)?,
                 is_default: m.is_default,
                 version: m.version.clone(),
+                signature: m.signature.clone(),
+                tensor_names: m.tensor_names.clone(),
+                session: m.session.clone(),
             })
         })
         .collect::<anyhow::Result<Vec<Model>>>()?;
@@ -84,17 +98,47 @@ async fn run(config: KnnConfig, args: KnnServiceArgs) -> Result<(), Box<dyn std:
         platform: config.platform,
         version: config.index_config.embedding_version,
         countries: config.countries,
+        experiment: config.model_config.experiment,
+        load_options: config.index_config.load_options,
     };
 
     let mut controller = KnnController::new(config);
     controller.load()?;
 
+    // Reloads and admin updates both replace the served indices, they must not overlap
+    let update_lock = Arc::new(Mutex::new(()));
+    if let Some(reload_interval_sec) = reload_interval_sec {
+        IndexReloader::new(
+            controller.knn_country(),
+            update_lock.clone(),
+            Duration::from_secs(reload_interval_sec),
+        )
+        .spawn();
+    }
+    // The admin service has no authentication, so it gets its own local-only listener
+    let admin_server = admin_port.map(|admin_port| {
+        let admin_addr = SocketAddr::from(([127, 0, 0, 1], admin_port));
+        let admin_controller = AdminController::new(controller.knn_country(), update_lock);
+        info!("Starting admin server on {}", admin_addr);
+        let admin_server = Server::builder()
+            .add_service(KnnAdminServer::new(admin_controller))
+            .serve(admin_addr);
+        tokio::spawn(async move {
+            if let Err(e) = admin_server.await {
+                error!("Admin server stopped: {}", e);
+            }
+        })
+    });
+
     info!("Starting server on {}", addr);
     Server::builder()
         .add_service(KnnServer::new(controller))
         .add_service(health_service)
         .serve(addr)
         .await?;
+    if let Some(admin_server) = admin_server {
+        admin_server.abort();
+    }
 
     info!("Stopping the server");
     Ok(())
@@ -1,11 +1,17 @@
 use config::{Config, Environment, File};
+use knn_rs::experiment::Experiment;
+use knn_rs::knn_tf::SessionConfig;
+use knn_rs::loader::LoadOptions;
 use serde::Deserialize;
-use std::{env, path::PathBuf};
+use std::{collections::HashMap, env, path::PathBuf};
 
 #[derive(Debug, Default, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ServerConfig {
     pub worker_thread: Option<u32>,
+    /// Port of the admin gRPC service, which can load and unload indices and models.
+    /// It only listens on the loopback interface, and isn't served when not set.
+    pub admin_port: Option<u16>,
 }
 
 #[derive(Debug, Default, Deserialize)]
@@ -13,6 +19,9 @@ pub struct ServerConfig {
 pub struct IndexConfig {
     pub embedding_version: String,
     pub indices_root: PathBuf,
+    pub reload_interval_sec: Option<u64>,
+    #[serde(default)]
+    pub load_options: LoadOptions,
 }
 
 #[derive(Debug, Default, Deserialize)]
@@ -23,12 +32,18 @@ pub struct Model {
     pub model_type: String,
     pub is_default: bool,
     pub version: Option<String>,
+    pub signature: Option<String>,
+    #[serde(default)]
+    pub tensor_names: HashMap<String, String>,
+    #[serde(default)]
+    pub session: SessionConfig,
 }
 
 #[derive(Debug, Default, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ModelConfig {
     pub models: Vec<Model>,
+    pub experiment: Option<Experiment>,
 }
 
 #[derive(Debug, Default, Deserialize)]